@@ -1,87 +1,268 @@
 use std::net::TcpStream;
 use std::time::Duration;
 
-use anyhow::{anyhow, bail, ensure, Result};
+use anyhow::{anyhow, ensure, Result};
 use async_io::{Async, Timer};
-use async_tungstenite::{tungstenite::Message, WebSocketStream};
-use futures::{future::FusedFuture, prelude::*, select};
+use async_tungstenite::WebSocketStream;
+use futures::{future::Fuse, prelude::*};
 use serde::Deserialize;
 use std::pin::Pin;
 use url::Url;
 
-use message::{command::*, event::*};
+use message::command::{ConnectionProperties, UpdateStatus};
+use message::event::*;
 use types::*;
 
+pub mod api_error;
+pub mod bus;
 pub mod client;
+pub mod collect;
+mod dispatch;
+pub mod embed;
+pub mod etf;
+pub mod handle;
+mod inflate;
 pub mod message;
+#[cfg(test)]
+pub mod mock;
+pub mod multipart;
+pub mod ratelimit;
+pub mod reconnect;
+pub mod record;
+pub mod session;
+pub mod shard;
 pub mod types;
 
+#[cfg(test)]
+mod tests;
+
 use client::Client;
+use handle::GatewayHandle;
+use reconnect::{Backoff, ReconnectPolicy};
+use shard::ShardManager;
 
 type WebSocket = WebSocketStream<async_tungstenite::async_tls::ClientStream<Async<TcpStream>>>;
 
-macro_rules! expect_message_or_bail {
-    ($stream:expr, $user_pat:pat = $message_type:ident => $result:expr) => {{
-        let next = $stream.next().await.ok_or_else(|| {
-            anyhow!(
-                "expected {} message following identification",
-                stringify!($message_type)
-            )
-        })?;
-        match next? {
-            Message::Text(s) => match serde_json::from_str(&s)? {
-                Event::$message_type($user_pat) => $result,
-                e => bail!(
-                    "first message received was not a {} message, got discriminant {:?}",
-                    stringify!($message_type),
-                    std::mem::discriminant(&e)
-                ),
-            },
-            m => bail!(m),
+/// Where the bot connects to. Defaults to the live Discord API, but every part
+/// can be overridden to point the bot at a local stand-in.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ConnectionConfig {
+    /// Root of the REST API, which every `Client` endpoint is relative to.
+    pub api_root: String,
+    /// Gateway URL to connect to. If unset, it is looked up through `gateway/bot`.
+    pub gateway_url: Option<String>,
+    /// Port of the gateway. If unset, the port of the gateway URL (or its scheme's default) is used.
+    pub gateway_port: Option<u16>,
+    /// Whether to connect to the gateway over TLS. If unset, `wss` URLs use TLS and `ws` URLs do not.
+    pub gateway_tls: Option<bool>,
+    /// Number of shards to run. If unset, the number recommended by `gateway/bot` is used.
+    pub shards: Option<u64>,
+    pub compression: Compression,
+    pub encoding: Encoding,
+    pub ordering: DispatchOrdering,
+    /// File to keep gateway sessions in, so they can be resumed after a restart. If unset, every
+    /// run starts new sessions.
+    pub session_file: Option<String>,
+    /// JSONL file to append all gateway traffic to, for `record::replay`. If unset, nothing is
+    /// recorded.
+    pub record_file: Option<String>,
+    pub reconnect: ReconnectPolicy,
+    /// Time between two identifies in the same rate limit bucket. Discord requires 5 seconds, so
+    /// this is only worth lowering for a gateway that does not rate limit identifies.
+    pub identify_interval_ms: u64,
+}
+
+impl ConnectionConfig {
+    pub fn identify_interval(&self) -> Duration {
+        Duration::from_millis(self.identify_interval_ms)
+    }
+}
+
+/// Transport compression of the gateway connection.
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    None,
+    /// The whole connection is one zlib stream, flushed after every message.
+    ZlibStream,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
+/// How gateway payloads are encoded.
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Json,
+    /// Erlang External Term Format, which is smaller on the wire than JSON.
+    Etf,
+}
+
+impl Encoding {
+    fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::Etf => "etf",
         }
-    }};
+    }
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding::Json
+    }
 }
 
-#[derive(Deserialize)]
-struct BotGateway {
-    url: String,
+/// Which dispatches are handled one after the other. Any others are handled side by side.
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DispatchOrdering {
+    /// Every dispatch waits for the one before it to be handled.
+    Sequential,
+    /// Dispatches about the same channel are handled in order, one at a time. Those not about a
+    /// channel, like `READY`, wait for everything before them and hold back everything after them.
+    PerChannel,
+}
+
+impl Default for DispatchOrdering {
+    fn default() -> Self {
+        DispatchOrdering::PerChannel
+    }
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        ConnectionConfig {
+            api_root: String::from(Client::DISCORD_ROOT),
+            gateway_url: None,
+            gateway_port: None,
+            gateway_tls: None,
+            shards: None,
+            compression: Compression::default(),
+            encoding: Encoding::default(),
+            ordering: DispatchOrdering::default(),
+            session_file: None,
+            record_file: None,
+            reconnect: ReconnectPolicy::default(),
+            identify_interval_ms: 5000,
+        }
+    }
+}
+
+/// What the bot tells the gateway about itself when identifying.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct GatewayOptions {
+    pub properties: ConnectionProperties,
+    /// Members a guild must have for the gateway to leave offline ones out of `GUILD_CREATE`,
+    /// from 50 to 250. If unset, the gateway's default of 50 is used.
+    pub large_threshold: Option<u8>,
+    /// Presence to start every session with, until one is set through the `GatewayHandle`.
+    pub presence: Option<UpdateStatus>,
+    /// Whether the gateway should compress large payloads one by one. This cannot be combined
+    /// with `Compression::ZlibStream`.
+    pub compress: bool,
+}
+
+impl GatewayOptions {
+    pub const LARGE_THRESHOLD_RANGE: std::ops::RangeInclusive<u8> = 50..=250;
+
+    /// Checks for options the gateway would reject, so that they are found before connecting.
+    pub fn validate(&self, connection: &ConnectionConfig) -> Result<()> {
+        if let Some(threshold) = self.large_threshold {
+            ensure!(
+                Self::LARGE_THRESHOLD_RANGE.contains(&threshold),
+                "large_threshold must be from {} to {}, got {}",
+                Self::LARGE_THRESHOLD_RANGE.start(),
+                Self::LARGE_THRESHOLD_RANGE.end(),
+                threshold
+            );
+        }
+        ensure!(
+            !(self.compress && connection.compression == Compression::ZlibStream),
+            "compress cannot be combined with zlib-stream compression"
+        );
+        Ok(())
+    }
 }
 
 pub struct Bot {
     client: Client,
     auth: TokenBuf,
     intents: Intents,
+    options: GatewayOptions,
+    connection: ConnectionConfig,
+    handle: GatewayHandle,
 }
 
 impl Bot {
-    pub fn new(auth: TokenBuf, intents: Intents) -> Self {
+    pub fn new(
+        auth: TokenBuf,
+        intents: Intents,
+        options: GatewayOptions,
+        connection: ConnectionConfig,
+    ) -> Self {
+        let handle = GatewayHandle::default();
+        if let Some(presence) = &options.presence {
+            handle.update_presence(presence.clone());
+        }
         Bot {
-            client: Client::new(&auth),
+            client: Client::new(&auth, &connection.api_root),
             auth,
             intents,
+            options,
+            connection,
+            handle,
         }
     }
 
-    async fn connect_to_gateway(&self) -> Result<WebSocket> {
+    /// A handle to the bot's gateway connection, for use by dispatch handlers.
+    pub fn handle(&self) -> GatewayHandle {
+        self.handle.clone()
+    }
+
+    /// The REST client the bot hands to dispatch handlers.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    async fn connect_to_gateway(&self, url: &str) -> Result<WebSocket> {
         const GATEWAY_VERSION: &str = "8";
-        const WSS_PORT: u16 = 443;
-        let gateway_url = self
-            .client
-            .make_get_request::<BotGateway>("gateway/bot")
-            .await?
-            .get_response_owned()?
-            .url;
-
-        let gateway_request = Url::parse_with_params(
-            &gateway_url,
-            &[("v", GATEWAY_VERSION), ("encoding", "json")],
+        let mut gateway_request = Url::parse_with_params(
+            url,
+            &[
+                ("v", GATEWAY_VERSION),
+                ("encoding", self.connection.encoding.as_str()),
+            ],
         )?;
+        if self.connection.compression == Compression::ZlibStream {
+            gateway_request
+                .query_pairs_mut()
+                .append_pair("compress", "zlib-stream");
+        }
+
+        // the websocket handshake picks plain or TLS based on the scheme alone
+        if let Some(tls) = self.connection.gateway_tls {
+            gateway_request
+                .set_scheme(if tls { "wss" } else { "ws" })
+                .map_err(|_| anyhow!("cannot set scheme of gateway url {}", gateway_request))?;
+        }
+        let port = self
+            .connection
+            .gateway_port
+            .or_else(|| gateway_request.port_or_known_default())
+            .ok_or_else(|| anyhow!("no port known for gateway url {}", gateway_request))?;
 
         let stream = Async::new(TcpStream::connect((
             gateway_request
                 .host_str()
-                .expect("Url must contain host name"),
-            WSS_PORT,
+                .ok_or_else(|| anyhow!("gateway url {} has no host name", gateway_request))?,
+            port,
         ))?)?;
 
         Ok(
@@ -91,186 +272,83 @@ impl Bot {
         )
     }
 
-    async fn opening_handshake(
-        &self,
-        ws: &mut WebSocket,
-        mut handler: impl AsyncDispatchHandler,
-    ) -> Result<State> {
-        send(
-            ws,
-            Identify {
-                token: self.auth.clone(),
-                properties: ConnectionProperties {
-                    os: "windows".to_string(),
-                    browser: "tungstenite".to_string(),
-                    device: "rust".to_string(),
-                },
-                intents: self.intents,
-                compress: None,
-                large_threshold: None,
-            },
-        )
-        .await?;
-
-        let heartbeat_interval = expect_message_or_bail!(ws, h = Hello => h.heartbeat_interval);
-        let (seq, session_id) = expect_message_or_bail!(ws,
-            d = Dispatch => match &d.payload {
-                    DispatchPayload::Ready(ready) => {
-                        let r = (d.seq, String::from(ready.session_id));
-                        handler.handle_message(d.payload, &self.client).await?;
-                        r
-                    },
-                p => bail!(
-                    "dispatch payload expected to be Ready, got discriminant {:?}",
-                    std::mem::discriminant(&p)
-                ),
-            }
-        );
-
-        Ok(State {
-            seq,
-            heartbeat_interval,
-            session_id,
-            heartbeat_acked: true,
-        })
+    /// Runs the bot, starting new sessions as laid out by the reconnect policy whenever one fails.
+    ///
+    /// Only returns once the gateway rejects the bot outright, the policy runs out of attempts,
+    /// or the bot is shut down through its `GatewayHandle`.
+    pub fn run(&self, handler: impl AsyncDispatchHandler) -> Result<()> {
+        async_io::block_on(self.run_async(handler))
     }
 
-    async fn reconnect(&self, ws: &mut WebSocket, state: &State) -> Result<()> {
-        *ws = self.connect_to_gateway().await?;
-        send(
-            ws,
-            Resume {
-                token: self.auth.clone(),
-                session_id: state.session_id.clone(),
-                seq: state.seq,
-            },
-        )
-        .await
-    }
-
-    async fn disconnect(&self, ws: &mut WebSocket) -> Result<()> {
-        ws.close(None).await?;
-        Ok(())
-    }
-
-    async fn handle_message(
+    /// Runs the bot like `run`, but shuts it down once `shutdown` completes.
+    pub fn run_until(
         &self,
-        ws: &mut WebSocket,
-        state: &mut State,
-        message: Message,
-        mut handler: impl AsyncDispatchHandler,
+        handler: impl AsyncDispatchHandler,
+        shutdown: impl Future<Output = ()>,
     ) -> Result<()> {
-        if let Message::Text(s) = &message {
-            println!("{}", s);
-            match serde_json::from_str::<Event>(s) {
-                Ok(Event::Dispatch(d)) => {
-                    if state.seq.0 + 1 != d.seq.0 {
-                        eprintln!("Sequence gap: previous = {} got = {}", state.seq.0, d.seq.0);
-                    }
-                    state.seq = d.seq;
-                    if let Err(e) = handler.handle_message(d.payload, &self.client).await {
-                        eprintln!("{}", e);
-                    }
+        async_io::block_on(async {
+            let mut run = Box::pin(self.run_async(handler).fuse());
+            let mut shutdown = Box::pin(shutdown.fuse());
+            futures::select! {
+                result = run => result,
+                () = shutdown => {
+                    self.handle.shut_down();
+                    run.await
                 }
-                Ok(Event::HeartbeatAck) => {
-                    println!("heartbeat acknowledged");
-                    state.heartbeat_acked = true;
-                }
-                Ok(Event::Reconnect) => {
-                    println!("disconnecting (reconnect received)");
-                    self.disconnect(ws).await?;
-                }
-                Ok(Event::InvalidSession(reconnect)) => {
-                    ensure!(reconnect, "Invalid session with false payload");
-                    println!("disconnecting (invalid session, expected reconnect)");
-                    self.disconnect(ws).await?
-                }
-                Err(e) => eprintln!("{}", e),
-                _ => (),
             }
-        }
-        Ok(())
+        })
     }
 
-    async fn run_loop(
-        &self,
-        ws: &mut WebSocket,
-        mut state: State,
-        mut handler: impl AsyncDispatchHandler,
-    ) -> Result<()> {
-        let mut timer = wait(state.heartbeat_interval);
-        loop {
-            let mut ws_fut = ws.next().fuse();
-            select! {
-                _ = timer => {
-                    if !state.heartbeat_acked {
-                        println!("disconnecting (heartbeat ack missed)");
-                        self.disconnect(ws).await?;
-                    } else {
-                        send(ws, Heartbeat(Some(state.seq))).await?;
-                        state.heartbeat_acked = false;
-                        timer = wait(state.heartbeat_interval);
-                    }
-                }
-                next = ws_fut => {
-                    match next {
-                        Some(msg) => self.handle_message(ws, &mut state, msg?, &mut handler).await?,
-                        None => {
-                            self.reconnect(ws, &state).await?;
-                            timer = wait(state.heartbeat_interval);
-                        }
+    async fn run_async(&self, handler: impl AsyncDispatchHandler) -> Result<()> {
+        let mut backoff = Backoff::new(&self.connection.reconnect);
+        let mut shutdown = Box::pin(self.handle.until_shut_down().fuse());
+        let manager = loop {
+            let mut start = Box::pin(
+                async {
+                    match ShardManager::new(self).await {
+                        Ok(manager) => Ok(Some(manager)),
+                        Err(e) => backoff.wait_after(e).await.map(|()| None),
                     }
                 }
+                .fuse(),
+            );
+            futures::select! {
+                result = start => if let Some(manager) = result? {
+                    break manager;
+                },
+                () = shutdown => return Ok(()),
             }
-        }
-    }
-
-    pub fn run(&self, mut handler: impl AsyncDispatchHandler) -> Result<()> {
-        async_io::block_on(async move {
-            let mut ws = self.connect_to_gateway().await?;
-            let state = dbg!(self.opening_handshake(&mut ws, &mut handler).await?);
-            self.run_loop(&mut ws, state, handler).await
-        })
+        };
+        manager.run(handler).await
     }
 }
 
 pub type AsyncDispatchFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + 'a>>;
 
+/// Handles the dispatches of every shard.
+///
+/// Dispatches may be handled concurrently, as the connection's `DispatchOrdering` allows, so
+/// handlers only get shared access to themselves.
 pub trait AsyncDispatchHandler {
     fn handle_message<'a>(
-        &'a mut self,
+        &'a self,
         payload: DispatchPayload<'a>,
+        shard: ShardInfo,
         client: &'a Client,
     ) -> AsyncDispatchFuture<'a>;
 }
 
-impl<T: AsyncDispatchHandler> AsyncDispatchHandler for &'_ mut T {
+impl<T: AsyncDispatchHandler> AsyncDispatchHandler for &'_ T {
     fn handle_message<'a>(
-        &'a mut self,
+        &'a self,
         payload: DispatchPayload<'a>,
+        shard: ShardInfo,
         client: &'a Client,
     ) -> AsyncDispatchFuture<'a> {
-        T::handle_message(*self, payload, client)
+        T::handle_message(*self, payload, shard, client)
     }
 }
 
-#[derive(Debug)]
-struct State {
-    seq: Sequence,
-    heartbeat_interval: u64,
-    session_id: String,
-    heartbeat_acked: bool,
-}
-
-fn wait(duration_millis: u64) -> impl FusedFuture {
+fn wait(duration_millis: u64) -> Fuse<Timer> {
     Timer::after(Duration::from_millis(duration_millis)).fuse()
 }
-
-async fn send(stream: &mut WebSocket, command: impl Command) -> Result<()> {
-    stream
-        .send(Message::Text(
-            serde_json::to_string(&CommandSerializer(command)).expect("Command serialization"),
-        ))
-        .await?;
-    Ok(())
-}
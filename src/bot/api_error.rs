@@ -0,0 +1,167 @@
+use std::fmt::{Display, Formatter};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+/// JSON error codes Discord may answer a REST request with.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ApiErrorCode {
+    General,
+    UnknownChannel,
+    UnknownGuild,
+    UnknownMember,
+    UnknownMessage,
+    UnknownUser,
+    UnknownEmoji,
+    MaxReactions,
+    RequestTooLarge,
+    MissingAccess,
+    CannotEditOthersMessage,
+    EmptyMessage,
+    CannotMessageUser,
+    MissingPermissions,
+    MessageTooOldToBulkDelete,
+    InvalidFormBody,
+    ReactionBlocked,
+    Other(u32),
+}
+
+impl From<u32> for ApiErrorCode {
+    fn from(code: u32) -> Self {
+        use ApiErrorCode::*;
+        match code {
+            0 => General,
+            10003 => UnknownChannel,
+            10004 => UnknownGuild,
+            10007 => UnknownMember,
+            10008 => UnknownMessage,
+            10013 => UnknownUser,
+            10014 => UnknownEmoji,
+            30010 => MaxReactions,
+            40005 => RequestTooLarge,
+            50001 => MissingAccess,
+            50005 => CannotEditOthersMessage,
+            50006 => EmptyMessage,
+            50007 => CannotMessageUser,
+            50013 => MissingPermissions,
+            50034 => MessageTooOldToBulkDelete,
+            50035 => InvalidFormBody,
+            90001 => ReactionBlocked,
+            n => Other(n),
+        }
+    }
+}
+
+impl Display for ApiErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        use ApiErrorCode::*;
+        match self {
+            General => f.write_str("0 general error"),
+            UnknownChannel => f.write_str("10003 unknown channel"),
+            UnknownGuild => f.write_str("10004 unknown guild"),
+            UnknownMember => f.write_str("10007 unknown member"),
+            UnknownMessage => f.write_str("10008 unknown message"),
+            UnknownUser => f.write_str("10013 unknown user"),
+            UnknownEmoji => f.write_str("10014 unknown emoji"),
+            MaxReactions => f.write_str("30010 maximum number of reactions reached"),
+            RequestTooLarge => f.write_str("40005 request entity too large"),
+            MissingAccess => f.write_str("50001 missing access"),
+            CannotEditOthersMessage => f.write_str("50005 cannot edit a message by another user"),
+            EmptyMessage => f.write_str("50006 cannot send an empty message"),
+            CannotMessageUser => f.write_str("50007 cannot send messages to this user"),
+            MissingPermissions => f.write_str("50013 missing permissions"),
+            MessageTooOldToBulkDelete => {
+                f.write_str("50034 message too old to bulk delete (older than 2 weeks)")
+            }
+            InvalidFormBody => f.write_str("50035 invalid form body"),
+            ReactionBlocked => f.write_str("90001 reaction blocked"),
+            Other(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+/// What was wrong with one field of a request Discord rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldError {
+    /// Where the field is in the request body, like `embeds.0.description`.
+    pub path: String,
+    pub code: String,
+    pub message: String,
+}
+
+/// Error returned when Discord answers a REST request with anything but success.
+#[derive(Clone, Debug)]
+pub struct DiscordApiError {
+    pub status: u16,
+    pub code: ApiErrorCode,
+    pub message: String,
+    pub errors: Vec<FieldError>,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    #[serde(default)]
+    code: u32,
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    errors: Value,
+}
+
+impl DiscordApiError {
+    /// Reads the error from the status and body of a response. Bodies that are not Discord's JSON
+    /// errors, like those of a proxy in between, only keep their status.
+    pub fn from_response(status: u16, body: &[u8]) -> Self {
+        let body = serde_json::from_slice(body).unwrap_or_else(|_| ErrorBody {
+            code: 0,
+            message: String::new(),
+            errors: Value::Null,
+        });
+        let mut errors = Vec::new();
+        collect_field_errors(&body.errors, String::new(), &mut errors);
+        DiscordApiError {
+            status,
+            code: ApiErrorCode::from(body.code),
+            message: body.message,
+            errors,
+        }
+    }
+}
+
+/// Flattens the nested `errors` object of an error response, whose leaves are `_errors` arrays.
+fn collect_field_errors(value: &Value, path: String, errors: &mut Vec<FieldError>) {
+    let object = match value {
+        Value::Object(object) => object,
+        _ => return,
+    };
+    for (key, value) in object {
+        if key == "_errors" {
+            for error in value.as_array().into_iter().flatten() {
+                errors.push(FieldError {
+                    path: path.clone(),
+                    code: error["code"].as_str().unwrap_or_default().to_string(),
+                    message: error["message"].as_str().unwrap_or_default().to_string(),
+                });
+            }
+        } else if path.is_empty() {
+            collect_field_errors(value, key.clone(), errors);
+        } else {
+            collect_field_errors(value, format!("{}.{}", path, key), errors);
+        }
+    }
+}
+
+impl Display for DiscordApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Discord API error {} ({})", self.status, self.code)?;
+        if !self.message.is_empty() {
+            write!(f, ": {}", self.message)?;
+        }
+        for error in &self.errors {
+            write!(f, "; {}: {} ({})", error.path, error.message, error.code)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DiscordApiError {}
@@ -0,0 +1,123 @@
+use std::cell::Cell;
+
+use futures::future;
+
+use super::client::Client;
+use super::message::event::*;
+use super::types::*;
+use super::{AsyncDispatchFuture, AsyncDispatchHandler};
+
+/// Whether a dispatch goes on to the handlers of an `EventBus`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    Stop,
+}
+
+/// Looks at every dispatch before the handlers of an `EventBus` do, and may keep it from them.
+pub trait Middleware {
+    fn process(&self, payload: &DispatchPayload<'_>, shard: ShardInfo) -> Flow;
+}
+
+impl<F: Fn(&DispatchPayload<'_>, ShardInfo) -> Flow> Middleware for F {
+    fn process(&self, payload: &DispatchPayload<'_>, shard: ShardInfo) -> Flow {
+        self(payload, shard)
+    }
+}
+
+/// Keeps the bot's own messages from handlers. The bot's ID is taken from `READY`, so this
+/// must see it.
+#[derive(Default)]
+pub struct IgnoreOwnMessages {
+    id: Cell<Option<Id>>,
+}
+
+impl Middleware for IgnoreOwnMessages {
+    fn process(&self, payload: &DispatchPayload<'_>, _shard: ShardInfo) -> Flow {
+        match payload {
+            DispatchPayload::Ready(ready) => self.id.set(Some(ready.user.id)),
+            DispatchPayload::MessageCreate(message) if self.id.get() == Some(message.author.id) => {
+                return Flow::Stop
+            }
+            _ => (),
+        }
+        Flow::Continue
+    }
+}
+
+/// Keeps dispatches about any of these channels from handlers.
+pub struct IgnoreChannels(pub Vec<Id>);
+
+impl Middleware for IgnoreChannels {
+    fn process(&self, payload: &DispatchPayload<'_>, _shard: ShardInfo) -> Flow {
+        match payload.channel_id() {
+            Some(channel) if self.0.contains(&channel) => Flow::Stop,
+            _ => Flow::Continue,
+        }
+    }
+}
+
+/// Passes every dispatch through its middleware, in the order they were added, and then to each
+/// of its handlers, highest priority first.
+///
+/// A bus is a handler itself, so it can be added to another bus to apply middleware to only
+/// some of its handlers.
+#[derive(Default)]
+pub struct EventBus<'h> {
+    middleware: Vec<Box<dyn Middleware + 'h>>,
+    handlers: Vec<(i32, Box<dyn AsyncDispatchHandler + 'h>)>,
+}
+
+impl<'h> EventBus<'h> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_middleware(mut self, middleware: impl Middleware + 'h) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Adds a handler. Handlers of the same priority run in the order they were added.
+    pub fn with_handler(mut self, priority: i32, handler: impl AsyncDispatchHandler + 'h) -> Self {
+        let index = self
+            .handlers
+            .iter()
+            .position(|(p, _)| *p < priority)
+            .unwrap_or(self.handlers.len());
+        self.handlers.insert(index, (priority, Box::new(handler)));
+        self
+    }
+}
+
+impl AsyncDispatchHandler for EventBus<'_> {
+    /// Every handler gets to run even if one before it fails. The first error is returned,
+    /// and any others are printed.
+    fn handle_message<'a>(
+        &'a self,
+        payload: DispatchPayload<'a>,
+        shard: ShardInfo,
+        client: &'a Client,
+    ) -> AsyncDispatchFuture<'a> {
+        if self
+            .middleware
+            .iter()
+            .any(|middleware| middleware.process(&payload, shard) == Flow::Stop)
+        {
+            return Box::pin(future::ready(Ok(())));
+        }
+        Box::pin(async move {
+            let mut result = Ok(());
+            for (_, handler) in &self.handlers {
+                if let Err(e) = handler.handle_message(payload.clone(), shard, client).await {
+                    if result.is_ok() {
+                        result = Err(e);
+                    } else {
+                        eprintln!("{}", e);
+                    }
+                }
+            }
+            result
+        })
+    }
+}
@@ -1,8 +1,15 @@
-use anyhow::Result;
+use anyhow::{bail, ensure, Result};
+use async_io::Timer;
+use chrono::Utc;
 use futures::prelude::*;
+use http::{Method, StatusCode};
 use isahc::HttpClientBuilder;
 use serde::{Deserialize, Serialize};
 
+use crate::bot::api_error::DiscordApiError;
+use crate::bot::embed::Embed;
+use crate::bot::multipart::{Attachment, FormData};
+use crate::bot::ratelimit::{RestLimiter, Route};
 use crate::bot::types::*;
 use serde::de::DeserializeOwned;
 use std::marker::PhantomData;
@@ -11,11 +18,190 @@ use std::time::{Duration, Instant};
 
 pub struct Client {
     http: isahc::HttpClient,
+    root: String,
+    /// Whether this is a stub that sends nothing.
+    offline: bool,
+    limiter: RestLimiter,
+}
+
+/// A message to send, built up from its parts. At least `content`, an embed or an attachment
+/// must be set.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct CreateMessage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    embed: Option<Embed>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_reference: Option<MessageReference>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allowed_mentions: Option<AllowedMentions>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    tts: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+    /// Sent as files alongside the rest, which goes in `payload_json`.
+    #[serde(skip)]
+    attachments: Vec<Attachment>,
+}
+
+impl CreateMessage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_content(mut self, content: impl Into<String>) -> Self {
+        self.content = Some(content.into());
+        self
+    }
+
+    pub fn with_embed(mut self, embed: Embed) -> Self {
+        self.embed = Some(embed);
+        self
+    }
+
+    /// Makes the message a reply to `message`, which must be in the channel it is sent to.
+    pub fn reply_to(mut self, message: &Message<'_>) -> Self {
+        self.message_reference = Some(MessageReference {
+            message_id: message.id,
+            channel_id: Some(message.channel_id),
+            guild_id: message.guild_id,
+        });
+        self
+    }
+
+    /// Limits who the message pings. By default, everyone mentioned in it is pinged.
+    pub fn with_allowed_mentions(mut self, allowed_mentions: AllowedMentions) -> Self {
+        self.allowed_mentions = Some(allowed_mentions);
+        self
+    }
+
+    /// Has the message read out loud to those viewing the channel.
+    pub fn with_tts(mut self, tts: bool) -> Self {
+        self.tts = tts;
+        self
+    }
+
+    /// Sets a value the `MESSAGE_CREATE` dispatch of the message will carry, to recognize it by.
+    pub fn with_nonce(mut self, nonce: impl Into<String>) -> Self {
+        self.nonce = Some(nonce.into());
+        self
+    }
+
+    /// Uploads a file with the message, up to `MAX_ATTACHMENTS` of them.
+    pub fn with_attachment(mut self, attachment: Attachment) -> Self {
+        self.attachments.push(attachment);
+        self
+    }
+}
+
+/// The message a message replies to.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct MessageReference {
+    pub message_id: Id,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel_id: Option<Id>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guild_id: Option<Id>,
+}
+
+/// Kinds of mentions that ping everyone they name.
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MentionType {
+    Roles,
+    Users,
+    Everyone,
+}
+
+/// Who a message may ping. The default pings no one.
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct AllowedMentions {
+    pub parse: Vec<MentionType>,
+    /// Users to ping even though `parse` does not include `Users`.
+    pub users: Vec<Id>,
+    /// Roles to ping even though `parse` does not include `Roles`.
+    pub roles: Vec<Id>,
+    /// Whether the author of the message replied to is pinged.
+    pub replied_user: bool,
+}
+
+impl AllowedMentions {
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    pub fn all() -> Self {
+        AllowedMentions {
+            parse: vec![
+                MentionType::Roles,
+                MentionType::Users,
+                MentionType::Everyone,
+            ],
+            replied_user: true,
+            ..Self::default()
+        }
+    }
+}
+
+/// Changes to make to a message. Parts left unset are kept as they are.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct EditMessage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    embed: Option<Embed>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allowed_mentions: Option<AllowedMentions>,
+}
+
+impl EditMessage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_content(mut self, content: impl Into<String>) -> Self {
+        self.content = Some(content.into());
+        self
+    }
+
+    pub fn with_embed(mut self, embed: Embed) -> Self {
+        self.embed = Some(embed);
+        self
+    }
+
+    pub fn with_allowed_mentions(mut self, allowed_mentions: AllowedMentions) -> Self {
+        self.allowed_mentions = Some(allowed_mentions);
+        self
+    }
+}
+
+/// How many files a message may be sent with.
+pub const MAX_ATTACHMENTS: usize = 10;
+/// How many messages a bulk delete may delete.
+pub const BULK_DELETE_RANGE: std::ops::RangeInclusive<usize> = 2..=100;
+/// How old, in milliseconds, messages may be to be bulk deleted.
+pub const BULK_DELETE_MAX_AGE: i64 = 14 * 24 * 60 * 60 * 1000;
+
+/// Whether the message with this ID is recent enough to be bulk deleted.
+pub fn can_bulk_delete(message: Id) -> bool {
+    Utc::now().timestamp_millis() - message.timestamp() < BULK_DELETE_MAX_AGE
+}
+
+/// Times a request is sent again after hitting a rate limit before giving up on it.
+const MAX_RETRIES: u32 = 5;
+
+/// Body of a response rejecting a request for hitting a rate limit.
+#[derive(Deserialize)]
+struct RateLimited {
+    /// Seconds until the request may be sent again.
+    retry_after: f64,
+    #[serde(default)]
+    global: bool,
 }
 
 pub struct Response<T> {
     inner: ResponseInner,
-    rate_limit_end: Option<Instant>,
     _phantom: PhantomData<T>,
 }
 
@@ -25,9 +211,9 @@ enum ResponseInner {
 }
 
 impl Client {
-    const DISCORD_ROOT: &'static str = "https://discord.com/api/";
+    pub const DISCORD_ROOT: &'static str = "https://discord.com/api";
 
-    pub fn new(auth: &Token) -> Self {
+    pub fn new(auth: &Token, root: &str) -> Self {
         Client {
             http: HttpClientBuilder::new()
                 .default_headers(&[
@@ -36,66 +222,272 @@ impl Client {
                 ])
                 .build()
                 .expect("isahc client initialization"),
+            root: root.trim_end_matches('/').to_string(),
+            offline: false,
+            limiter: RestLimiter::default(),
+        }
+    }
+
+    /// A client that sends nothing, for replaying recorded events. Requests that change
+    /// something are printed and succeed, while requests for data fail.
+    pub fn stub() -> Self {
+        Client {
+            http: isahc::HttpClient::new().expect("isahc client initialization"),
+            root: String::new(),
+            offline: true,
+            limiter: RestLimiter::default(),
         }
     }
 
-    fn get_discord_endpoint(endpoint: &str) -> String {
-        format!("{}/{}", Self::DISCORD_ROOT, endpoint)
+    fn get_discord_endpoint(&self, endpoint: &str) -> String {
+        format!("{}/{}", self.root, endpoint.trim_start_matches('/'))
+    }
+
+    /// Sends a request, first waiting out any rate limit it falls under. Requests rejected for
+    /// hitting a rate limit are sent again once it ends.
+    ///
+    /// Responses other than success are returned as a `DiscordApiError`. Bodies are sent as JSON
+    /// unless another `content_type` is given.
+    async fn send(
+        &self,
+        method: Method,
+        endpoint: &str,
+        body: impl Into<Vec<u8>>,
+        content_type: Option<&str>,
+    ) -> Result<http::Response<isahc::Body>> {
+        let body = body.into();
+        let route = Route::new(method.as_str(), endpoint);
+        let turn = self.limiter.turn(&route);
+        let _turn = turn.lock().await;
+        let mut retries = 0;
+        loop {
+            while let Some(delay) = self.limiter.delay(&route, Instant::now()) {
+                println!(
+                    "waiting {}ms for the rate limit of {}",
+                    delay.as_millis(),
+                    route
+                );
+                Timer::after(delay).await;
+            }
+            let mut request = http::Request::builder()
+                .method(method.clone())
+                .uri(self.get_discord_endpoint(endpoint));
+            if let Some(content_type) = content_type {
+                request = request.header("Content-Type", content_type);
+            }
+            let request = request.body(body.clone())?;
+            let mut response = self.http.send_async(request).await?;
+            self.limiter
+                .update(&route, response.headers(), Instant::now());
+            let status = response.status();
+            if status.is_success() {
+                return Ok(response);
+            }
+
+            let mut bytes = Vec::new();
+            response.body_mut().read_to_end(&mut bytes).await?;
+            if status != StatusCode::TOO_MANY_REQUESTS || retries == MAX_RETRIES {
+                bail!(DiscordApiError::from_response(status.as_u16(), &bytes));
+            }
+            let limited = serde_json::from_slice::<RateLimited>(&bytes).ok();
+            let retry_after = limited
+                .as_ref()
+                .map(|limited| limited.retry_after)
+                .or_else(|| get_from_response(&response, "Retry-After"))
+                .unwrap_or(1.0);
+            let global = matches!(limited, Some(RateLimited { global: true, .. }))
+                || get_from_response(&response, "X-RateLimit-Global").unwrap_or(false);
+            retries += 1;
+            println!(
+                "hit the {} rate limit on {}, retrying in {}s",
+                if global { "global" } else { "route's" },
+                route,
+                retry_after
+            );
+            self.limiter.limited(
+                &route,
+                Duration::from_secs_f64(retry_after),
+                global,
+                Instant::now(),
+            );
+        }
     }
 
     pub async fn make_get_request<T>(&self, endpoint: &str) -> Result<Response<T>> {
-        let response = self
-            .http
-            .get_async(dbg!(Self::get_discord_endpoint(endpoint)))
-            .await?;
+        if self.offline {
+            bail!("cannot GET {} without a connection", endpoint);
+        }
+        let response = self.send(Method::GET, endpoint, Vec::new(), None).await?;
+        Ok(Response {
+            inner: ResponseInner::Response(response.into_body()),
+            _phantom: PhantomData,
+        })
+    }
+
+    pub async fn make_put_request(&self, endpoint: &str, body: String) -> Result<()> {
+        if self.offline {
+            println!("PUT {} {}", endpoint, body);
+            return Ok(());
+        }
+        self.send(Method::PUT, endpoint, body, None).await?;
+        Ok(())
+    }
 
-        let rate_limit_end = get_from_response::<usize, _>(&response, "X-RateLimit-Remaining")
-            .and_then(|remaining| {
-                if remaining == 0 {
-                    let limit_end_after = get_from_response(&response, "X-RateLimit-Reset-After")?;
-                    Some(Instant::now() + Duration::from_secs(limit_end_after))
-                } else {
-                    None
-                }
+    pub async fn make_post_request<T>(&self, endpoint: &str, body: String) -> Result<Response<T>> {
+        if self.offline {
+            println!("POST {} {}", endpoint, body);
+            return Ok(Response {
+                inner: ResponseInner::Bytes(Vec::new()),
+                _phantom: PhantomData,
             });
+        }
+        let response = self.send(Method::POST, endpoint, body, None).await?;
+        Ok(Response {
+            inner: ResponseInner::Response(response.into_body()),
+            _phantom: PhantomData,
+        })
+    }
 
+    pub async fn make_patch_request<T>(&self, endpoint: &str, body: String) -> Result<Response<T>> {
+        if self.offline {
+            println!("PATCH {} {}", endpoint, body);
+            return Ok(Response {
+                inner: ResponseInner::Bytes(Vec::new()),
+                _phantom: PhantomData,
+            });
+        }
+        let response = self.send(Method::PATCH, endpoint, body, None).await?;
         Ok(Response {
             inner: ResponseInner::Response(response.into_body()),
-            rate_limit_end: dbg!(rate_limit_end),
             _phantom: PhantomData,
         })
     }
 
-    pub async fn make_put_request(&self, endpoint: &str, body: String) -> Result<()> {
-        let response = self
-            .http
-            .put_async(Self::get_discord_endpoint(endpoint), dbg!(body))
+    pub async fn make_delete_request(&self, endpoint: &str) -> Result<()> {
+        if self.offline {
+            println!("DELETE {}", endpoint);
+            return Ok(());
+        }
+        self.send(Method::DELETE, endpoint, Vec::new(), None)
             .await?;
-        dbg!(response);
         Ok(())
     }
 
-    pub async fn make_post_request(&self, endpoint: &str, body: String) -> Result<()> {
+    /// Posts `payload_json` along with files, as `multipart/form-data`.
+    pub async fn make_multipart_request<T>(
+        &self,
+        endpoint: &str,
+        payload_json: String,
+        attachments: &[Attachment],
+    ) -> Result<Response<T>> {
+        if self.offline {
+            let names: Vec<_> = attachments.iter().map(|a| a.filename.as_str()).collect();
+            println!(
+                "POST {} {} with {}",
+                endpoint,
+                payload_json,
+                names.join(", ")
+            );
+            return Ok(Response {
+                inner: ResponseInner::Bytes(Vec::new()),
+                _phantom: PhantomData,
+            });
+        }
+        let form = FormData::new(&payload_json, attachments);
+        let content_type = form.content_type();
         let response = self
-            .http
-            .post_async(Self::get_discord_endpoint(endpoint), dbg!(body))
+            .send(
+                Method::POST,
+                endpoint,
+                form.into_body(),
+                Some(&content_type),
+            )
             .await?;
-        dbg!(response);
-        Ok(())
+        Ok(Response {
+            inner: ResponseInner::Response(response.into_body()),
+            _phantom: PhantomData,
+        })
     }
 
+    /// Sends a message with just `content`.
     pub async fn create_message(&self, channel_id: Id, content: &str) -> Result<()> {
+        self.send_message(channel_id, &CreateMessage::new().with_content(content))
+            .await?;
+        Ok(())
+    }
+
+    /// Sends a message, and returns it as created. Stub clients have nothing to return.
+    ///
+    /// Messages that are empty or have too many files fail without being sent, since Discord
+    /// would reject them.
+    pub async fn send_message<'a>(
+        &self,
+        channel_id: Id,
+        message: &CreateMessage,
+    ) -> Result<Response<Message<'a>>> {
+        ensure!(
+            message.attachments.len() <= MAX_ATTACHMENTS,
+            "can only send {} files with a message, got {}",
+            MAX_ATTACHMENTS,
+            message.attachments.len()
+        );
+        ensure!(
+            message.content.is_some() || message.embed.is_some() || !message.attachments.is_empty(),
+            "cannot send a message without content, an embed or files"
+        );
+        let endpoint = format!("/channels/{}/messages", channel_id);
+        let payload = serde_json::to_string(message).expect("Cannot format message to create ");
+        if message.attachments.is_empty() {
+            self.make_post_request(&endpoint, payload).await
+        } else {
+            self.make_multipart_request(&endpoint, payload, &message.attachments)
+                .await
+        }
+    }
+
+    /// Changes a message the bot sent, and returns it as changed. Parts left unset are kept.
+    pub async fn edit_message<'a>(
+        &self,
+        channel: Id,
+        message: Id,
+        edit: &EditMessage,
+    ) -> Result<Response<Message<'a>>> {
+        self.make_patch_request(
+            &format!("/channels/{}/messages/{}", channel, message),
+            serde_json::to_string(edit).expect("Cannot format message edit"),
+        )
+        .await
+    }
+
+    pub async fn delete_message(&self, channel: Id, message: Id) -> Result<()> {
+        self.make_delete_request(&format!("/channels/{}/messages/{}", channel, message))
+            .await
+    }
+
+    /// Deletes from 2 to 100 messages at once, none of them older than two weeks.
+    ///
+    /// Requests breaking those limits fail without being sent, since Discord would reject them.
+    pub async fn bulk_delete_messages(&self, channel: Id, messages: &[Id]) -> Result<()> {
+        ensure!(
+            BULK_DELETE_RANGE.contains(&messages.len()),
+            "can only bulk delete {} to {} messages, got {}",
+            BULK_DELETE_RANGE.start(),
+            BULK_DELETE_RANGE.end(),
+            messages.len()
+        );
+        if let Some(old) = messages.iter().find(|&&id| !can_bulk_delete(id)) {
+            bail!("message {} is too old to bulk delete", old);
+        }
+
         #[derive(Serialize)]
-        struct CreateMessage<'a> {
-            content: &'a str,
+        struct BulkDelete<'a> {
+            messages: &'a [Id],
         }
-        self.make_post_request(
-            &format!("/channels/{}/messages", channel_id),
-            serde_json::to_string(&CreateMessage { content })
-                .expect("Cannot format message to create "),
+        self.make_post_request::<()>(
+            &format!("/channels/{}/messages/bulk-delete", channel),
+            serde_json::to_string(&BulkDelete { messages }).expect("Cannot format bulk delete"),
         )
         .await?;
-
         Ok(())
     }
 
@@ -106,8 +498,7 @@ impl Client {
             "/channels/{}/messages/{}/reactions/{}/@me",
             channel, message, encoded_emoji
         );
-        self.make_put_request(&dbg!(endpoint), String::default())
-            .await?;
+        self.make_put_request(&endpoint, String::default()).await?;
         Ok(())
     }
 
@@ -124,12 +515,6 @@ impl Client {
     }
 }
 
-impl<T> Response<T> {
-    pub fn rate_limit_end(&self) -> Option<Instant> {
-        self.rate_limit_end
-    }
-}
-
 impl<'a, 'de, T> Response<T>
 where
     'a: 'de,
@@ -146,10 +531,7 @@ where
             self.inner = ResponseInner::Bytes(bytes);
         }
         match &self.inner {
-            ResponseInner::Bytes(bytes) => {
-                dbg!(String::from_utf8_lossy(bytes));
-                Ok(serde_json::from_slice(bytes)?)
-            }
+            ResponseInner::Bytes(bytes) => Ok(serde_json::from_slice(bytes)?),
             _ => unreachable!(),
         }
     }
@@ -0,0 +1,93 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_io::Timer;
+use futures::channel::mpsc;
+use futures::prelude::*;
+
+use super::dispatch::ReceivedEvent;
+use super::message::event::*;
+use super::types::*;
+
+/// A dispatch that outlives the handler call it arrived with, as received by a `Collector`.
+#[derive(Clone, Debug)]
+pub struct OwnedDispatch {
+    pub shard: ShardInfo,
+    event: ReceivedEvent,
+}
+
+impl OwnedDispatch {
+    pub fn payload(&self) -> DispatchPayload<'_> {
+        match self.event.payload() {
+            Ok(payload) => payload,
+            Err(_) => unreachable!("collected dispatches were parsed when received"),
+        }
+    }
+}
+
+/// Something waiting for dispatches that `predicate` accepts.
+pub(crate) struct Waiter {
+    predicate: Box<dyn Fn(&DispatchPayload<'_>) -> bool>,
+    sender: mpsc::UnboundedSender<OwnedDispatch>,
+}
+
+impl Waiter {
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+/// Passes a dispatch to every waiter that accepts it, with the event it was parsed from.
+pub(crate) fn offer(
+    waiters: &[Waiter],
+    shard: ShardInfo,
+    payload: &DispatchPayload<'_>,
+    event: &ReceivedEvent,
+) {
+    for waiter in waiters {
+        if (waiter.predicate)(payload) {
+            let _ = waiter.sender.unbounded_send(OwnedDispatch {
+                shard,
+                event: event.clone(),
+            });
+        }
+    }
+}
+
+/// A stream of the dispatches accepted by a predicate, which ends once its time is up.
+///
+/// Only dispatches received while the collector exists are collected.
+pub struct Collector {
+    events: mpsc::UnboundedReceiver<OwnedDispatch>,
+    deadline: Timer,
+}
+
+impl Collector {
+    pub(crate) fn new(
+        predicate: impl Fn(&DispatchPayload<'_>) -> bool + 'static,
+        deadline: Timer,
+    ) -> (Self, Waiter) {
+        let (sender, events) = mpsc::unbounded();
+        let waiter = Waiter {
+            predicate: Box::new(predicate),
+            sender,
+        };
+        (Collector { events, deadline }, waiter)
+    }
+}
+
+impl Stream for Collector {
+    type Item = OwnedDispatch;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<OwnedDispatch>> {
+        // ends the stream once closed below
+        if let Poll::Ready(dispatch) = self.events.poll_next_unpin(cx) {
+            return Poll::Ready(dispatch);
+        }
+        if Pin::new(&mut self.deadline).poll(cx).is_ready() {
+            self.events.close();
+            return self.events.poll_next_unpin(cx);
+        }
+        Poll::Pending
+    }
+}
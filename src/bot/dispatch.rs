@@ -0,0 +1,126 @@
+use std::collections::VecDeque;
+use std::fmt::{self, Debug, Formatter};
+use std::rc::Rc;
+
+use anyhow::{bail, Result};
+
+use super::etf;
+use super::message::event::*;
+use super::types::*;
+use super::{DispatchOrdering, Encoding};
+
+/// A gateway event as its shard received it. It is kept encoded, so that it can be shared, and
+/// is parsed wherever it is used.
+#[derive(Clone)]
+pub(crate) struct ReceivedEvent {
+    encoding: Encoding,
+    frame: Rc<[u8]>,
+}
+
+impl ReceivedEvent {
+    pub fn new(encoding: Encoding, frame: Vec<u8>) -> Self {
+        ReceivedEvent {
+            encoding,
+            frame: Rc::from(frame),
+        }
+    }
+
+    pub fn parse(&self) -> Result<Event<'_>> {
+        Ok(match self.encoding {
+            Encoding::Json => serde_json::from_slice(&self.frame)?,
+            Encoding::Etf => etf::from_slice(&self.frame)?,
+        })
+    }
+
+    /// The payload of the event, failing if it is not a dispatch.
+    pub fn payload(&self) -> Result<DispatchPayload<'_>> {
+        match self.parse()? {
+            Event::Dispatch(dispatch) => Ok(dispatch.payload),
+            _ => bail!("event is not a dispatch"),
+        }
+    }
+}
+
+impl Debug for ReceivedEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReceivedEvent")
+            .field("payload", &self.payload().ok())
+            .finish()
+    }
+}
+
+/// A dispatch received by a shard, waiting to be handled.
+pub(crate) struct QueuedEvent {
+    pub shard: ShardInfo,
+    pub event: ReceivedEvent,
+    /// Channel the dispatch is about, if any.
+    pub lane: Option<Id>,
+}
+
+/// Decides which received dispatches may be handled, so that handlers can run side by side
+/// while still keeping to a `DispatchOrdering`.
+pub(crate) struct DispatchQueue {
+    ordering: DispatchOrdering,
+    pending: VecDeque<QueuedEvent>,
+    /// Lanes of the dispatches being handled.
+    running: Vec<Option<Id>>,
+}
+
+impl DispatchQueue {
+    pub fn new(ordering: DispatchOrdering) -> Self {
+        DispatchQueue {
+            ordering,
+            pending: VecDeque::new(),
+            running: Vec::new(),
+        }
+    }
+
+    /// Queues a dispatch, which is about `channel` if that is set.
+    pub fn push(&mut self, shard: ShardInfo, event: ReceivedEvent, channel: Option<Id>) {
+        let lane = match self.ordering {
+            DispatchOrdering::Sequential => None,
+            DispatchOrdering::PerChannel => channel,
+        };
+        self.pending.push_back(QueuedEvent { shard, event, lane });
+    }
+
+    /// Takes the next dispatch that may start being handled now, if any.
+    ///
+    /// Dispatches about a channel wait for the ones before them in the same channel. Dispatches
+    /// that are not about a channel wait for every dispatch before them, and hold back every
+    /// dispatch after them.
+    pub fn next_ready(&mut self) -> Option<QueuedEvent> {
+        if self.running.contains(&None) {
+            return None;
+        }
+        let index = self
+            .pending
+            .iter()
+            .enumerate()
+            .find_map(|(i, event)| match event.lane {
+                None if i == 0 && self.running.is_empty() => Some(Some(i)),
+                None => Some(None),
+                Some(_)
+                    if self.running.contains(&event.lane)
+                        || self.pending.iter().take(i).any(|e| e.lane == event.lane) =>
+                {
+                    None
+                }
+                Some(_) => Some(Some(i)),
+            })??;
+        let event = self.pending.remove(index)?;
+        self.running.push(event.lane);
+        Some(event)
+    }
+
+    /// Marks a dispatch of `lane` as handled.
+    pub fn finish(&mut self, lane: Option<Id>) {
+        if let Some(i) = self.running.iter().position(|l| *l == lane) {
+            self.running.swap_remove(i);
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_empty() && self.running.is_empty()
+    }
+}
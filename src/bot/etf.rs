@@ -0,0 +1,731 @@
+//! Erlang External Term Format, as spoken by the gateway with `encoding=etf`.
+//!
+//! Only the terms the gateway uses are supported. Atoms decode as strings, except for `nil`,
+//! `true` and `false`, which decode as unit and booleans. Binaries decode as strings when they are
+//! valid UTF-8. Tuples decode as sequences, and improper lists are rejected.
+//!
+//! Strings serialize as binaries, `None` and unit as `nil`, struct field names as atoms, and both
+//! sequences and tuples as lists.
+
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+
+use serde::de::value::{MapAccessDeserializer, SeqDeserializer};
+use serde::de::{self, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::{ser, Deserialize, Serialize};
+
+const VERSION: u8 = 131;
+
+const NEW_FLOAT_EXT: u8 = 70;
+const SMALL_INTEGER_EXT: u8 = 97;
+const INTEGER_EXT: u8 = 98;
+const FLOAT_EXT: u8 = 99;
+const ATOM_EXT: u8 = 100;
+const SMALL_TUPLE_EXT: u8 = 104;
+const LARGE_TUPLE_EXT: u8 = 105;
+const NIL_EXT: u8 = 106;
+const STRING_EXT: u8 = 107;
+const LIST_EXT: u8 = 108;
+const BINARY_EXT: u8 = 109;
+const SMALL_BIG_EXT: u8 = 110;
+const LARGE_BIG_EXT: u8 = 111;
+const MAP_EXT: u8 = 116;
+const ATOM_UTF8_EXT: u8 = 118;
+const SMALL_ATOM_UTF8_EXT: u8 = 119;
+
+#[derive(Debug)]
+pub struct Error(String);
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "etf: {}", self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Decodes a value from a whole ETF message, including its version byte.
+pub fn from_slice<'de, T: Deserialize<'de>>(input: &'de [u8]) -> Result<T> {
+    match input.split_first() {
+        Some((&VERSION, term)) => from_term(term),
+        Some(_) => Err(Error(String::from("unsupported format version"))),
+        None => Err(Error(String::from("unexpected end of input"))),
+    }
+}
+
+/// Encodes a value as a whole ETF message, including its version byte.
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    let mut ser = Serializer {
+        output: vec![VERSION],
+    };
+    value.serialize(&mut ser)?;
+    Ok(ser.output)
+}
+
+/// Decodes a value from a single term without a version byte, like one captured with
+/// `RAW_TERM_TOKEN`.
+pub fn from_term<'de, T: Deserialize<'de>>(input: &'de [u8]) -> Result<T> {
+    let mut de = Deserializer { input };
+    let value = T::deserialize(&mut de)?;
+    if !de.input.is_empty() {
+        return Err(Error(String::from("trailing bytes after term")));
+    }
+    Ok(value)
+}
+
+/// Name of a newtype struct that, instead of being decoded, is visited with the bytes of the
+/// next term, like `serde_json`'s `RawValue`.
+pub const RAW_TERM_TOKEN: &str = "$taco_bot::etf::RawTerm";
+
+pub struct Deserializer<'de> {
+    input: &'de [u8],
+}
+
+impl<'de> Deserializer<'de> {
+    fn read_bytes(&mut self, len: usize) -> Result<&'de [u8]> {
+        if self.input.len() < len {
+            return Err(Error(String::from("unexpected end of input")));
+        }
+        let (bytes, rest) = self.input.split_at(len);
+        self.input = rest;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_str(&mut self, len: usize) -> Result<&'de str> {
+        std::str::from_utf8(self.read_bytes(len)?).map_err(|e| Error(e.to_string()))
+    }
+
+    /// Reads the name of an atom whose tag has already been read.
+    fn read_atom(&mut self, tag: u8) -> Result<&'de str> {
+        let len = match tag {
+            ATOM_EXT | ATOM_UTF8_EXT => self.read_u16()? as usize,
+            _ => self.read_u8()? as usize,
+        };
+        self.read_str(len)
+    }
+
+    /// Whether the next term is the atom `nil`.
+    fn peek_nil(&self) -> bool {
+        matches!(
+            self.input,
+            [ATOM_EXT, 0, 3, b'n', b'i', b'l', ..]
+                | [ATOM_UTF8_EXT, 0, 3, b'n', b'i', b'l', ..]
+                | [SMALL_ATOM_UTF8_EXT, 3, b'n', b'i', b'l', ..]
+        )
+    }
+
+    fn read_big<V: Visitor<'de>>(&mut self, len: usize, visitor: V) -> Result<V::Value> {
+        let negative = self.read_u8()? != 0;
+        let digits = self.read_bytes(len)?;
+        if digits.iter().skip(8).any(|&d| d != 0) {
+            return Err(Error(String::from("integer does not fit in 64 bits")));
+        }
+        let magnitude = digits
+            .iter()
+            .take(8)
+            .rev()
+            .fold(0u64, |n, &d| (n << 8) | u64::from(d));
+        if !negative {
+            visitor.visit_u64(magnitude)
+        } else {
+            match i64::try_from(-i128::from(magnitude)) {
+                Ok(n) => visitor.visit_i64(n),
+                Err(_) => Err(Error(String::from("integer does not fit in 64 bits"))),
+            }
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.read_u8()? {
+            SMALL_INTEGER_EXT => visitor.visit_u8(self.read_u8()?),
+            INTEGER_EXT => visitor.visit_i32(self.read_u32()? as i32),
+            NEW_FLOAT_EXT => {
+                let bytes = self.read_bytes(8)?;
+                let mut bits = [0; 8];
+                bits.copy_from_slice(bytes);
+                visitor.visit_f64(f64::from_be_bytes(bits))
+            }
+            FLOAT_EXT => {
+                let s = self.read_str(31)?.trim_end_matches('\0');
+                visitor.visit_f64(
+                    s.trim()
+                        .parse()
+                        .map_err(|_| Error(format!("bad float {}", s)))?,
+                )
+            }
+            tag @ ATOM_EXT | tag @ SMALL_ATOM_UTF8_EXT | tag @ ATOM_UTF8_EXT => {
+                match self.read_atom(tag)? {
+                    "nil" => visitor.visit_unit(),
+                    "true" => visitor.visit_bool(true),
+                    "false" => visitor.visit_bool(false),
+                    name => visitor.visit_borrowed_str(name),
+                }
+            }
+            SMALL_TUPLE_EXT => {
+                let len = self.read_u8()? as usize;
+                self.visit_elements(len, false, visitor)
+            }
+            LARGE_TUPLE_EXT => {
+                let len = self.read_u32()? as usize;
+                self.visit_elements(len, false, visitor)
+            }
+            NIL_EXT => {
+                visitor.visit_seq(SeqDeserializer::<_, Error>::new(std::iter::empty::<u8>()))
+            }
+            STRING_EXT => {
+                let len = self.read_u16()? as usize;
+                let bytes = self.read_bytes(len)?;
+                visitor.visit_seq(SeqDeserializer::<_, Error>::new(bytes.iter().copied()))
+            }
+            LIST_EXT => {
+                let len = self.read_u32()? as usize;
+                self.visit_elements(len, true, visitor)
+            }
+            BINARY_EXT => {
+                let len = self.read_u32()? as usize;
+                let bytes = self.read_bytes(len)?;
+                match std::str::from_utf8(bytes) {
+                    Ok(s) => visitor.visit_borrowed_str(s),
+                    Err(_) => visitor.visit_borrowed_bytes(bytes),
+                }
+            }
+            SMALL_BIG_EXT => {
+                let len = self.read_u8()? as usize;
+                self.read_big(len, visitor)
+            }
+            LARGE_BIG_EXT => {
+                let len = self.read_u32()? as usize;
+                self.read_big(len, visitor)
+            }
+            MAP_EXT => {
+                let len = self.read_u32()? as usize;
+                visitor.visit_map(Entries { de: self, len })
+            }
+            tag => Err(Error(format!("unsupported term tag {}", tag))),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if self.peek_nil() {
+            self.deserialize_any(de::IgnoredAny)?;
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        if name == RAW_TERM_TOKEN {
+            let start = self.input;
+            self.deserialize_any(de::IgnoredAny)?;
+            let len = start.len() - self.input.len();
+            return visitor.visit_borrowed_bytes(&start[..len]);
+        }
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self.input.first() {
+            Some(&MAP_EXT) => {
+                self.read_u8()?;
+                let len = self.read_u32()? as usize;
+                visitor.visit_enum(MapAccessDeserializer::new(Entries { de: self, len }))
+            }
+            _ => {
+                let variant: &str = Deserialize::deserialize(&mut *self)?;
+                visitor.visit_enum(variant.into_deserializer())
+            }
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+impl<'de> Deserializer<'de> {
+    fn visit_elements<V: Visitor<'de>>(
+        &mut self,
+        len: usize,
+        list: bool,
+        visitor: V,
+    ) -> Result<V::Value> {
+        let mut elements = Elements { de: self, len };
+        let value = visitor.visit_seq(&mut elements)?;
+        if elements.len != 0 {
+            return Err(Error(String::from("not every element was read")));
+        }
+        if list && self.read_u8()? != NIL_EXT {
+            return Err(Error(String::from("improper lists are not supported")));
+        }
+        Ok(value)
+    }
+}
+
+struct Elements<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    len: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for Elements<'_, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if self.len == 0 {
+            return Ok(None);
+        }
+        self.len -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+struct Entries<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    len: usize,
+}
+
+impl<'de> de::MapAccess<'de> for Entries<'_, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        if self.len == 0 {
+            return Ok(None);
+        }
+        self.len -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+pub struct Serializer {
+    output: Vec<u8>,
+}
+
+impl Serializer {
+    fn write_u16(&mut self, n: u16) {
+        self.output.extend_from_slice(&n.to_be_bytes());
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.output.extend_from_slice(&n.to_be_bytes());
+    }
+
+    fn write_len(&mut self, len: usize) -> Result<()> {
+        let len = u32::try_from(len).map_err(|_| Error(String::from("term too long")))?;
+        self.write_u32(len);
+        Ok(())
+    }
+
+    fn write_atom(&mut self, name: &str) -> Result<()> {
+        match u8::try_from(name.len()) {
+            Ok(len) => {
+                self.output.push(SMALL_ATOM_UTF8_EXT);
+                self.output.push(len);
+            }
+            Err(_) => {
+                let len =
+                    u16::try_from(name.len()).map_err(|_| Error(String::from("atom too long")))?;
+                self.output.push(ATOM_UTF8_EXT);
+                self.write_u16(len);
+            }
+        }
+        self.output.extend_from_slice(name.as_bytes());
+        Ok(())
+    }
+
+    fn write_binary(&mut self, bytes: &[u8]) -> Result<()> {
+        self.output.push(BINARY_EXT);
+        self.write_len(bytes.len())?;
+        self.output.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_big(&mut self, negative: bool, magnitude: u64) {
+        let digits = magnitude.to_le_bytes();
+        let len = 8 - magnitude.leading_zeros() as usize / 8;
+        self.output.push(SMALL_BIG_EXT);
+        self.output.push(len as u8);
+        self.output.push(negative as u8);
+        self.output.extend_from_slice(&digits[..len]);
+    }
+
+    /// Starts a list or map whose length is only known at the end.
+    fn begin(&mut self, tag: u8) -> Compound<'_> {
+        let start = self.output.len();
+        self.output.push(tag);
+        self.write_u32(0);
+        Compound {
+            ser: self,
+            start,
+            len: 0,
+        }
+    }
+
+    /// Starts a single-entry map keyed by the name of an enum variant.
+    fn begin_variant(&mut self, variant: &str) -> Result<()> {
+        self.output.push(MAP_EXT);
+        self.write_u32(1);
+        self.write_binary(variant.as_bytes())
+    }
+}
+
+/// A list or map being serialized, whose length is patched in once it ends.
+pub struct Compound<'a> {
+    ser: &'a mut Serializer,
+    start: usize,
+    len: usize,
+}
+
+impl Compound<'_> {
+    fn end_list(mut self) -> Result<()> {
+        if self.len == 0 {
+            self.ser.output.truncate(self.start);
+        } else {
+            self.patch_len()?;
+        }
+        self.ser.output.push(NIL_EXT);
+        Ok(())
+    }
+
+    fn end_map(mut self) -> Result<()> {
+        self.patch_len()
+    }
+
+    fn patch_len(&mut self) -> Result<()> {
+        let len = u32::try_from(self.len).map_err(|_| Error(String::from("term too long")))?;
+        self.ser.output[self.start + 1..self.start + 5].copy_from_slice(&len.to_be_bytes());
+        Ok(())
+    }
+}
+
+impl<'a> ser::Serializer for &'a mut Serializer {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Compound<'a>;
+    type SerializeTuple = Compound<'a>;
+    type SerializeTupleStruct = Compound<'a>;
+    type SerializeTupleVariant = Compound<'a>;
+    type SerializeMap = Compound<'a>;
+    type SerializeStruct = Compound<'a>;
+    type SerializeStructVariant = Compound<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.write_atom(if v { "true" } else { "false" })
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        if let Ok(small) = u8::try_from(v) {
+            self.output.push(SMALL_INTEGER_EXT);
+            self.output.push(small);
+        } else if let Ok(int) = i32::try_from(v) {
+            self.output.push(INTEGER_EXT);
+            self.write_u32(int as u32);
+        } else {
+            self.write_big(v < 0, v.unsigned_abs());
+        }
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        match i64::try_from(v) {
+            Ok(v) => self.serialize_i64(v),
+            Err(_) => {
+                self.write_big(false, v);
+                Ok(())
+            }
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        self.serialize_f64(f64::from(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        self.output.push(NEW_FLOAT_EXT);
+        self.output.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        self.write_binary(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        self.write_binary(v)
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        self.write_atom("nil")
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        self.write_atom("nil")
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<()> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.begin_variant(variant)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Compound<'a>> {
+        Ok(self.begin(LIST_EXT))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Compound<'a>> {
+        Ok(self.begin(LIST_EXT))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Compound<'a>> {
+        Ok(self.begin(LIST_EXT))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>> {
+        self.begin_variant(variant)?;
+        Ok(self.begin(LIST_EXT))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Compound<'a>> {
+        Ok(self.begin(MAP_EXT))
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Compound<'a>> {
+        Ok(self.begin(MAP_EXT))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>> {
+        self.begin_variant(variant)?;
+        Ok(self.begin(MAP_EXT))
+    }
+}
+
+impl ser::SerializeSeq for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.len += 1;
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<()> {
+        self.end_list()
+    }
+}
+
+impl ser::SerializeTuple for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.end_list()
+    }
+}
+
+impl ser::SerializeTupleStruct for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.end_list()
+    }
+}
+
+impl ser::SerializeTupleVariant for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.end_list()
+    }
+}
+
+impl ser::SerializeMap for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        self.len += 1;
+        key.serialize(&mut *self.ser)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<()> {
+        self.end_map()
+    }
+}
+
+impl ser::SerializeStruct for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.len += 1;
+        self.ser.write_atom(key)?;
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<()> {
+        self.end_map()
+    }
+}
+
+impl ser::SerializeStructVariant for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        ser::SerializeStruct::serialize_field(self, key, value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.end_map()
+    }
+}
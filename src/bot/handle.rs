@@ -0,0 +1,312 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use async_io::Timer;
+use futures::channel::{mpsc, oneshot};
+use futures::{Future, FutureExt, StreamExt};
+
+use super::collect::{self, Collector, OwnedDispatch, Waiter};
+use super::dispatch::ReceivedEvent;
+
+use super::message::command::{RequestGuildMembers, UpdateStatus};
+use super::message::event::{DispatchPayload, GuildMembersChunk};
+use super::types::*;
+
+/// Number of heartbeats the average latency is taken over.
+const LATENCY_SAMPLES: usize = 10;
+
+/// A command for a running shard, sent through a `GatewayHandle`.
+pub(crate) enum ShardCommand {
+    UpdateStatus(UpdateStatus),
+    RequestGuildMembers(RequestGuildMembers),
+    /// Closes the connection for good.
+    Shutdown,
+}
+
+/// The answer to a `RequestGuildMembers`, put together from all of its chunks.
+#[derive(Clone, Debug, Default)]
+pub struct GuildMembers {
+    pub members: Vec<GuildMember>,
+    /// Requested user IDs that are not members of the guild.
+    pub not_found: Vec<Id>,
+}
+
+/// A member request whose chunks have not all arrived yet.
+struct PendingMembers {
+    /// The shard whose session the request was sent on.
+    shard: u64,
+    members: GuildMembers,
+    done: oneshot::Sender<GuildMembers>,
+}
+
+/// Forgets a member request once nothing waits for its answer anymore.
+struct PendingGuard<'a> {
+    pending: &'a RefCell<HashMap<String, PendingMembers>>,
+    nonce: String,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.borrow_mut().remove(&self.nonce);
+    }
+}
+
+/// A handle to the gateway connections of a `Bot`, which can be kept by dispatch handlers.
+#[derive(Clone, Default)]
+pub struct GatewayHandle {
+    latency: Rc<RefCell<HashMap<u64, VecDeque<Duration>>>>,
+    presence: Rc<RefCell<Option<UpdateStatus>>>,
+    shards: Rc<RefCell<HashMap<u64, mpsc::UnboundedSender<ShardCommand>>>>,
+    pending_members: Rc<RefCell<HashMap<String, PendingMembers>>>,
+    next_nonce: Rc<Cell<u64>>,
+    shutting_down: Rc<Cell<bool>>,
+    /// Told once the bot is shut down.
+    shutdown_waiters: Rc<RefCell<Vec<oneshot::Sender<()>>>>,
+    waiters: Rc<RefCell<Vec<Waiter>>>,
+}
+
+impl GatewayHandle {
+    /// The presence last set through this handle, if any.
+    pub fn presence(&self) -> Option<UpdateStatus> {
+        self.presence.borrow().clone()
+    }
+
+    /// Changes the bot's status and activity on every shard.
+    ///
+    /// The presence is kept when sessions are started again.
+    pub fn set_presence(&self, status: Status, activity: Option<Activity>) {
+        self.update_presence(UpdateStatus {
+            status,
+            activities: activity.into_iter().collect(),
+            ..UpdateStatus::default()
+        })
+    }
+
+    /// Changes the bot's activity on every shard, keeping its status.
+    pub fn set_activity(&self, activity: Option<Activity>) {
+        let status = self
+            .presence
+            .borrow()
+            .as_ref()
+            .map_or(Status::Online, |presence| presence.status);
+        self.set_presence(status, activity)
+    }
+
+    pub fn update_presence(&self, presence: UpdateStatus) {
+        *self.presence.borrow_mut() = Some(presence.clone());
+        for shard in self.shards.borrow().values() {
+            let _ = shard.unbounded_send(ShardCommand::UpdateStatus(presence.clone()));
+        }
+    }
+
+    /// Asks the gateway for members of a guild, and waits for all of them to arrive.
+    ///
+    /// A nonce is picked for the request unless it has one already, which must not be the nonce
+    /// of a request still waiting for its answer. If the session of the guild's shard ends before
+    /// the answer is complete, this fails. Dropping the returned future gives up on the request.
+    pub async fn request_guild_members(
+        &self,
+        mut request: RequestGuildMembers,
+    ) -> Result<GuildMembers> {
+        let nonce = match &request.nonce {
+            Some(nonce) => nonce.clone(),
+            None => {
+                let nonce = self.next_nonce.get();
+                self.next_nonce.set(nonce + 1);
+                request
+                    .nonce
+                    .get_or_insert(format!("members-{}", nonce))
+                    .clone()
+            }
+        };
+        if self.pending_members.borrow().contains_key(&nonce) {
+            bail!("member request {} is already waiting for its answer", nonce);
+        }
+
+        let (shard_id, shard) = self.shard_of(request.guild_id)?;
+        let (done, answer) = oneshot::channel();
+        self.pending_members.borrow_mut().insert(
+            nonce.clone(),
+            PendingMembers {
+                shard: shard_id,
+                members: GuildMembers::default(),
+                done,
+            },
+        );
+        let _guard = PendingGuard {
+            pending: &self.pending_members,
+            nonce: nonce.clone(),
+        };
+        if shard
+            .unbounded_send(ShardCommand::RequestGuildMembers(request))
+            .is_err()
+        {
+            return Err(anyhow!("shard {} is not running", shard_id));
+        }
+
+        answer
+            .await
+            .map_err(|_| anyhow!("member request {} was dropped", nonce))
+    }
+
+    /// Asks the gateway for members of a guild without waiting for them. They arrive as
+    /// `GUILD_MEMBERS_CHUNK` dispatches, like any other.
+    pub fn send_guild_members_request(&self, request: RequestGuildMembers) -> Result<()> {
+        let (shard_id, shard) = self.shard_of(request.guild_id)?;
+        shard
+            .unbounded_send(ShardCommand::RequestGuildMembers(request))
+            .map_err(|_| anyhow!("shard {} is not running", shard_id))
+    }
+
+    /// The running shard that `guild` is on.
+    fn shard_of(&self, guild: Id) -> Result<(u64, mpsc::UnboundedSender<ShardCommand>)> {
+        // guilds are spread over shards by the timestamp part of their ID
+        let shard_count = self.shards.borrow().len().max(1) as u64;
+        let shard_id = (guild.get() >> 22) % shard_count;
+        let shard = self
+            .shards
+            .borrow()
+            .get(&shard_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("shard {} is not running", shard_id))?;
+        Ok((shard_id, shard))
+    }
+
+    /// Waits for the next dispatch that `predicate` accepts, for up to `timeout`.
+    ///
+    /// Only dispatches received from now on are looked at, so to not miss a quick answer to a
+    /// message, `collect` before sending it.
+    ///
+    /// The dispatch is still passed to handlers as usual. Since it is looked at as soon as it is
+    /// received, handlers may wait for dispatches that are queued behind them.
+    pub async fn wait_for(
+        &self,
+        predicate: impl Fn(&DispatchPayload<'_>) -> bool + 'static,
+        timeout: Duration,
+    ) -> Option<OwnedDispatch> {
+        self.collect(predicate, timeout).next().await
+    }
+
+    /// Collects the dispatches that `predicate` accepts for up to `timeout`, like `wait_for`.
+    pub fn collect(
+        &self,
+        predicate: impl Fn(&DispatchPayload<'_>) -> bool + 'static,
+        timeout: Duration,
+    ) -> Collector {
+        let (collector, waiter) = Collector::new(predicate, Timer::after(timeout));
+        self.waiters.borrow_mut().push(waiter);
+        collector
+    }
+
+    /// Passes a dispatch to the collectors waiting for it.
+    pub(crate) fn receive_dispatch(
+        &self,
+        shard: ShardInfo,
+        payload: &DispatchPayload<'_>,
+        event: &ReceivedEvent,
+    ) {
+        let mut waiters = self.waiters.borrow_mut();
+        waiters.retain(|waiter| !waiter.is_closed());
+        collect::offer(&waiters, shard, payload, event);
+    }
+
+    /// Adds a chunk to the member request it answers, completing it with the last chunk.
+    pub(crate) fn receive_member_chunk(&self, chunk: &GuildMembersChunk) {
+        let nonce = match &chunk.nonce {
+            Some(nonce) => nonce.as_str(),
+            None => return,
+        };
+        let mut pending = self.pending_members.borrow_mut();
+        let request = match pending.get_mut(nonce) {
+            Some(request) => request,
+            None => return,
+        };
+        request.members.members.extend(
+            chunk
+                .members
+                .iter()
+                .filter_map(|member| member.to_guild_member()),
+        );
+        request
+            .members
+            .not_found
+            .extend(chunk.not_found.iter().copied());
+        if chunk.chunk_index + 1 >= chunk.chunk_count {
+            if let Some(request) = pending.remove(nonce) {
+                let _ = request.done.send(request.members);
+            }
+        }
+    }
+
+    /// Fails the member requests sent to `shard`, whose session ended before answering them.
+    pub(crate) fn drop_member_requests(&self, shard: u64) {
+        self.pending_members
+            .borrow_mut()
+            .retain(|_, request| request.shard != shard);
+    }
+
+    /// Closes every shard's connection and stops the bot, once the dispatches already received
+    /// are handled.
+    pub fn shut_down(&self) {
+        self.shutting_down.set(true);
+        for shard in self.shards.borrow().values() {
+            let _ = shard.unbounded_send(ShardCommand::Shutdown);
+        }
+        for waiter in self.shutdown_waiters.borrow_mut().drain(..) {
+            let _ = waiter.send(());
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.get()
+    }
+
+    /// Completes once the bot is shut down, for waits that no shard is around to cut short.
+    pub(crate) fn until_shut_down(&self) -> impl Future<Output = ()> {
+        let (sender, receiver) = oneshot::channel();
+        if self.is_shutting_down() {
+            let _ = sender.send(());
+        } else {
+            self.shutdown_waiters.borrow_mut().push(sender);
+        }
+        receiver.map(|_| ())
+    }
+
+    /// Registers a running shard to pass commands on to.
+    pub(crate) fn attach_shard(&self, shard: u64) -> mpsc::UnboundedReceiver<ShardCommand> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.shards.borrow_mut().insert(shard, sender);
+        receiver
+    }
+
+    /// Time between the last acknowledged heartbeat of a shard and its acknowledgement.
+    pub fn latency(&self, shard: u64) -> Option<Duration> {
+        self.latency
+            .borrow()
+            .get(&shard)
+            .and_then(|samples| samples.back().copied())
+    }
+
+    /// Average latency of a shard over its last few acknowledged heartbeats.
+    pub fn average_latency(&self, shard: u64) -> Option<Duration> {
+        let latency = self.latency.borrow();
+        match latency.get(&shard) {
+            Some(samples) if !samples.is_empty() => {
+                Some(samples.iter().sum::<Duration>() / samples.len() as u32)
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn record_latency(&self, shard: u64, latency: Duration) {
+        let mut latencies = self.latency.borrow_mut();
+        let samples = latencies.entry(shard).or_default();
+        if samples.len() == LATENCY_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+    }
+}
@@ -0,0 +1,60 @@
+use std::io::Read;
+
+use anyhow::Result;
+use flate2::read::ZlibDecoder;
+use flate2::{Decompress, FlushDecompress};
+
+/// Marks the end of every complete message in a zlib-stream.
+const ZLIB_SUFFIX: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Inflates the binary frames of a `compress=zlib-stream` gateway connection.
+///
+/// The whole connection is a single zlib stream, so one of these must be kept per connection
+/// and fed every binary frame in order.
+pub(crate) struct ZlibStream {
+    inflate: Decompress,
+    buffer: Vec<u8>,
+}
+
+impl ZlibStream {
+    pub fn new() -> Self {
+        ZlibStream {
+            inflate: Decompress::new(true),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Adds a binary frame to the stream, and returns the message it completes, if any.
+    pub fn push(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.buffer.extend_from_slice(frame);
+        if !self.buffer.ends_with(&ZLIB_SUFFIX) {
+            return Ok(None);
+        }
+
+        let mut out = Vec::with_capacity(self.buffer.len() * 4);
+        let start = self.inflate.total_in();
+        loop {
+            let consumed = (self.inflate.total_in() - start) as usize;
+            self.inflate.decompress_vec(
+                &self.buffer[consumed..],
+                &mut out,
+                FlushDecompress::Sync,
+            )?;
+            let consumed = (self.inflate.total_in() - start) as usize;
+            if consumed == self.buffer.len() && out.len() < out.capacity() {
+                break;
+            }
+            out.reserve(out.capacity());
+        }
+        self.buffer.clear();
+        Ok(Some(out))
+    }
+}
+
+/// Inflates a payload the gateway compressed on its own, as it does for bots that identify with
+/// `compress`.
+pub(crate) fn inflate_payload(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() * 4);
+    ZlibDecoder::new(data).read_to_end(&mut out)?;
+    Ok(out)
+}
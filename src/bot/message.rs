@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 
 use crate::bot::types::*;
+use crate::strings::StrCow;
 
 pub mod command {
     use serde::ser::SerializeStruct;
@@ -40,34 +41,108 @@ pub mod command {
         pub intents: Intents,
         pub compress: Option<bool>,
         pub large_threshold: Option<u8>,
+        pub shard: ShardInfo,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub presence: Option<UpdateStatus>,
     }
 
     impl Command for Identify {
         const OP: u8 = 2;
     }
 
-    #[derive(Serialize)]
+    /// What the bot tells the gateway it runs on when identifying.
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+    #[serde(default)]
     pub struct ConnectionProperties {
-        #[serde(rename = "$os")]
+        #[serde(rename(serialize = "$os"))]
         pub os: String,
-        #[serde(rename = "$browser")]
+        #[serde(rename(serialize = "$browser"))]
         pub browser: String,
-        #[serde(rename = "$device")]
+        #[serde(rename(serialize = "$device"))]
         pub device: String,
     }
 
-    #[derive(Serialize)]
+    impl Default for ConnectionProperties {
+        fn default() -> Self {
+            ConnectionProperties {
+                os: std::env::consts::OS.to_string(),
+                browser: "tungstenite".to_string(),
+                device: "rust".to_string(),
+            }
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    #[serde(default)]
     pub struct UpdateStatus {
-        pub since: Option<i32>,
+        /// Unix time in milliseconds since when the bot has been idle.
+        pub since: Option<u64>,
         pub status: Status,
         pub afk: bool,
-        pub activities: Option<Never>, // omitted for now -- might implement later
+        pub activities: Vec<Activity>,
+    }
+
+    impl Default for UpdateStatus {
+        fn default() -> Self {
+            UpdateStatus {
+                since: None,
+                status: Status::Online,
+                afk: false,
+                activities: Vec::new(),
+            }
+        }
     }
 
     impl Command for UpdateStatus {
         const OP: u8 = 3;
     }
 
+    /// Asks for members of a guild, which arrive as `GUILD_MEMBERS_CHUNK` dispatches.
+    ///
+    /// Either `query` or `user_ids` must be set.
+    #[derive(Serialize, Clone, Debug)]
+    pub struct RequestGuildMembers {
+        pub guild_id: Id,
+        /// Prefix of the usernames to look for. An empty query asks for every member.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub query: Option<String>,
+        /// Maximum number of members to send, or 0 for no limit when asking for every member.
+        pub limit: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub user_ids: Option<Vec<Id>>,
+        /// Echoed back in every chunk of the answer, to tell answers apart.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub nonce: Option<String>,
+    }
+
+    impl RequestGuildMembers {
+        /// Asks for the members whose username starts with `query`.
+        pub fn query(guild_id: Id, query: impl Into<String>, limit: u32) -> Self {
+            RequestGuildMembers {
+                guild_id,
+                query: Some(query.into()),
+                limit,
+                user_ids: None,
+                nonce: None,
+            }
+        }
+
+        /// Asks for specific members.
+        pub fn user_ids(guild_id: Id, user_ids: Vec<Id>) -> Self {
+            RequestGuildMembers {
+                guild_id,
+                query: None,
+                limit: 0,
+                user_ids: Some(user_ids),
+                nonce: None,
+            }
+        }
+    }
+
+    impl Command for RequestGuildMembers {
+        const OP: u8 = 8;
+    }
+
     #[derive(Serialize)]
     pub struct Resume {
         pub token: TokenBuf,
@@ -78,18 +153,19 @@ pub mod command {
     impl Command for Resume {
         const OP: u8 = 6;
     }
-
-    #[derive(Serialize)]
-    pub enum Never {}
 }
 
 pub mod event {
     use std::convert::TryFrom;
+    use std::fmt::{Display, Formatter};
 
-    use serde::de::{Error, Unexpected};
+    use anyhow::{anyhow, bail};
+    use serde::de::{Error, IgnoredAny, Visitor};
     use serde::Deserializer;
     use serde_json::value::RawValue;
 
+    use crate::bot::etf;
+
     use super::*;
 
     #[derive(Deserialize)]
@@ -101,7 +177,7 @@ pub mod event {
         InvalidSession(bool),
         Hello(Hello),
         HeartbeatAck,
-        // Heartbeat?
+        Heartbeat,
     }
 
     pub struct Dispatch<'a> {
@@ -109,27 +185,173 @@ pub mod event {
         pub payload: DispatchPayload<'a>,
     }
 
-    #[derive(Debug)]
+    #[derive(Clone, Debug)]
     pub enum DispatchPayload<'a> {
         MessageCreate(Message<'a>),
         // more to be added later
         Ready(Ready<'a>),
+        Resumed,
         TypingStart(TypingStart<'a>),
+        GuildMembersChunk(GuildMembersChunk<'a>),
+        /// Any event without its own variant, left unparsed for handlers that want it.
+        Unknown {
+            event_type: &'a str,
+            /// The channel the event is about, if any.
+            channel_id: Option<Id>,
+            raw: Box<RawValue>,
+        },
+    }
+
+    impl DispatchPayload<'_> {
+        /// The channel the dispatch is about, if any.
+        pub fn channel_id(&self) -> Option<Id> {
+            match self {
+                DispatchPayload::MessageCreate(message) => Some(message.channel_id),
+                DispatchPayload::TypingStart(typing) => Some(typing.channel_id),
+                DispatchPayload::Unknown { channel_id, .. } => *channel_id,
+                _ => None,
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct ChannelOf {
+        channel_id: Option<Id>,
+    }
+
+    /// Close codes the gateway may close the connection with.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub enum GatewayCloseCode {
+        UnknownError,
+        UnknownOpcode,
+        DecodeError,
+        NotAuthenticated,
+        AuthenticationFailed,
+        AlreadyAuthenticated,
+        InvalidSeq,
+        RateLimited,
+        SessionTimedOut,
+        InvalidShard,
+        ShardingRequired,
+        InvalidApiVersion,
+        InvalidIntents,
+        DisallowedIntents,
+        Other(u16),
+    }
+
+    /// What to do after the gateway closed the connection.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub enum CloseAction {
+        /// Reconnect and resume the current session.
+        Resume,
+        /// Reconnect and identify with a new session.
+        Identify,
+        /// Give up; reconnecting will fail the same way.
+        Fatal,
+    }
+
+    impl GatewayCloseCode {
+        pub fn action(self) -> CloseAction {
+            use GatewayCloseCode::*;
+            match self {
+                UnknownError | UnknownOpcode | DecodeError | AlreadyAuthenticated | RateLimited
+                | Other(_) => CloseAction::Resume,
+                NotAuthenticated | InvalidSeq | SessionTimedOut => CloseAction::Identify,
+                AuthenticationFailed | InvalidShard | ShardingRequired | InvalidApiVersion
+                | InvalidIntents | DisallowedIntents => CloseAction::Fatal,
+            }
+        }
+
+        pub fn is_fatal(self) -> bool {
+            self.action() == CloseAction::Fatal
+        }
+    }
+
+    impl From<u16> for GatewayCloseCode {
+        fn from(code: u16) -> Self {
+            use GatewayCloseCode::*;
+            match code {
+                4000 => UnknownError,
+                4001 => UnknownOpcode,
+                4002 => DecodeError,
+                4003 => NotAuthenticated,
+                4004 => AuthenticationFailed,
+                4005 => AlreadyAuthenticated,
+                4007 => InvalidSeq,
+                4008 => RateLimited,
+                4009 => SessionTimedOut,
+                4010 => InvalidShard,
+                4011 => ShardingRequired,
+                4012 => InvalidApiVersion,
+                4013 => InvalidIntents,
+                4014 => DisallowedIntents,
+                n => Other(n),
+            }
+        }
     }
 
+    impl Display for GatewayCloseCode {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            use GatewayCloseCode::*;
+            match self {
+                UnknownError => f.write_str("4000 unknown error"),
+                UnknownOpcode => f.write_str("4001 unknown opcode"),
+                DecodeError => f.write_str("4002 decode error"),
+                NotAuthenticated => f.write_str("4003 not authenticated"),
+                AuthenticationFailed => {
+                    f.write_str("4004 authentication failed (check the bot token)")
+                }
+                AlreadyAuthenticated => f.write_str("4005 already authenticated"),
+                InvalidSeq => f.write_str("4007 invalid sequence number"),
+                RateLimited => f.write_str("4008 rate limited"),
+                SessionTimedOut => f.write_str("4009 session timed out"),
+                InvalidShard => f.write_str("4010 invalid shard"),
+                ShardingRequired => f.write_str("4011 sharding required"),
+                InvalidApiVersion => f.write_str("4012 invalid API version"),
+                InvalidIntents => f.write_str("4013 invalid intents"),
+                DisallowedIntents => f.write_str(
+                    "4014 disallowed intents (privileged intents must be enabled for the bot)",
+                ),
+                Other(n) => write!(f, "{}", n),
+            }
+        }
+    }
+
+    /// Error returned when the gateway closes the connection in a way the bot cannot recover from.
+    #[derive(Debug)]
+    pub struct GatewayClosed {
+        pub code: GatewayCloseCode,
+        pub reason: String,
+    }
+
+    impl Display for GatewayClosed {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "gateway closed the connection: {}", self.code)?;
+            if !self.reason.is_empty() {
+                write!(f, " ({})", self.reason)?;
+            }
+            Ok(())
+        }
+    }
+
+    impl std::error::Error for GatewayClosed {}
+
     #[derive(Deserialize)]
     pub struct Hello {
         pub heartbeat_interval: u64,
     }
 
-    #[derive(Deserialize, Debug)]
+    #[derive(Deserialize, Clone, Debug)]
     pub struct Ready<'a> {
         #[serde(borrow)]
         pub user: User<'a>,
         pub session_id: &'a str,
+        /// Where to connect to resume the session, if not the gateway URL.
+        #[serde(default)]
+        pub resume_gateway_url: Option<&'a str>,
     }
 
-    #[derive(Deserialize, Debug)]
+    #[derive(Deserialize, Clone, Debug)]
     pub struct TypingStart<'a> {
         channel_id: Id,
         guild_id: Option<Id>,
@@ -140,85 +362,152 @@ pub mod event {
         member: Option<Member<'a>>,
     }
 
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct GuildMembersChunk<'a> {
+        pub guild_id: Id,
+        #[serde(borrow)]
+        pub members: Vec<Member<'a>>,
+        pub chunk_index: u32,
+        pub chunk_count: u32,
+        /// Requested user IDs that are not members of the guild.
+        #[serde(default)]
+        pub not_found: Vec<Id>,
+        #[serde(borrow)]
+        pub nonce: Option<StrCow<'a>>,
+    }
+
+    pub const OP_DISPATCH: u8 = 0;
+    pub const OP_HEARTBEAT: u8 = 1;
+    pub const OP_RECONNECT: u8 = 7;
+    pub const OP_INVALID_SESSION: u8 = 9;
+    pub const OP_HELLO: u8 = 10;
+    pub const OP_HEARTBEAT_ACK: u8 = 11;
+
+    /// The envelope of an event, with the payload left unparsed.
     #[derive(Deserialize)]
-    struct RawEvent<'a> {
-        op: u8,
-        t: Option<&'a str>,
-        s: Option<Sequence>,
+    pub struct RawEvent<'a> {
+        pub op: u8,
+        pub t: Option<&'a str>,
+        pub s: Option<Sequence>,
 
         #[serde(borrow)]
-        d: &'a RawValue,
+        pub d: RawPayload<'a>,
+    }
+
+    /// An unparsed payload, in the encoding of the event it came with.
+    #[derive(Copy, Clone, Debug)]
+    pub enum RawPayload<'a> {
+        Json(&'a RawValue),
+        /// A single ETF term, without a version byte.
+        Etf(&'a [u8]),
+    }
+
+    impl<'a> RawPayload<'a> {
+        /// Parses the whole payload as a `T`.
+        pub fn decode<T: Deserialize<'a>>(self) -> anyhow::Result<T> {
+            match self {
+                RawPayload::Json(raw) => {
+                    let mut de = serde_json::Deserializer::from_str(raw.get());
+                    let value = T::deserialize(&mut de)?;
+                    de.end()?;
+                    Ok(value)
+                }
+                RawPayload::Etf(term) => Ok(etf::from_term(term)?),
+            }
+        }
+
+        /// The payload as JSON, re-encoding it if it is not JSON already.
+        pub fn to_raw_value(self) -> anyhow::Result<Box<RawValue>> {
+            match self {
+                RawPayload::Json(raw) => Ok(raw.to_owned()),
+                RawPayload::Etf(term) => Ok(serde_json::value::to_raw_value(&etf::from_term::<
+                    serde_json::Value,
+                >(
+                    term
+                )?)?),
+            }
+        }
+    }
+
+    impl<'de: 'a, 'a> Deserialize<'de> for RawPayload<'a> {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            struct RawPayloadVisitor;
+
+            impl<'de> Visitor<'de> for RawPayloadVisitor {
+                type Value = RawPayload<'de>;
+
+                fn expecting(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+                    f.write_str("a gateway payload")
+                }
+
+                // only the ETF deserializer knows the token, and hands over the term it is at
+                fn visit_borrowed_bytes<E: Error>(self, term: &'de [u8]) -> Result<Self::Value, E> {
+                    Ok(RawPayload::Etf(term))
+                }
+
+                fn visit_newtype_struct<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+                where
+                    D: Deserializer<'de>,
+                {
+                    <&RawValue>::deserialize(deserializer).map(RawPayload::Json)
+                }
+            }
+
+            deserializer.deserialize_newtype_struct(etf::RAW_TERM_TOKEN, RawPayloadVisitor)
+        }
     }
 
     impl<'a> TryFrom<RawEvent<'a>> for Event<'a> {
-        type Error = serde_json::Error;
+        type Error = anyhow::Error;
 
         fn try_from(raw: RawEvent<'a>) -> Result<Self, Self::Error> {
-            fn get_dispatch<'de: 'a, 'a, D>(
-                de: D,
-                t: &str,
+            fn get_dispatch<'a>(
+                d: RawPayload<'a>,
+                t: &'a str,
                 seq: Sequence,
-            ) -> Result<Dispatch<'a>, <Event as TryFrom<RawEvent>>::Error>
-            where
-                D: Deserializer<'de, Error = serde_json::Error>,
-            {
+            ) -> anyhow::Result<Dispatch<'a>> {
                 let payload = match t {
-                    "MESSAGE_CREATE" => {
-                        Message::deserialize(de).map(DispatchPayload::MessageCreate)
-                    }
-                    "READY" => Ready::deserialize(de).map(DispatchPayload::Ready),
-                    "TYPING_START" => {
-                        TypingStart::deserialize(de).map(DispatchPayload::TypingStart)
-                    }
-                    s => Err(serde_json::Error::invalid_value(
-                        Unexpected::Str(s),
-                        &"valid gateway message type",
-                    )),
-                }?;
+                    "MESSAGE_CREATE" => DispatchPayload::MessageCreate(d.decode()?),
+                    "READY" => DispatchPayload::Ready(d.decode()?),
+                    "RESUMED" => d.decode::<IgnoredAny>().map(|_| DispatchPayload::Resumed)?,
+                    "TYPING_START" => DispatchPayload::TypingStart(d.decode()?),
+                    "GUILD_MEMBERS_CHUNK" => DispatchPayload::GuildMembersChunk(d.decode()?),
+                    event_type => DispatchPayload::Unknown {
+                        event_type,
+                        channel_id: d.decode::<ChannelOf>().ok().and_then(|of| of.channel_id),
+                        raw: d.to_raw_value()?,
+                    },
+                };
                 Ok(Dispatch { seq, payload })
             }
-            fn deserialize_null<'de, D>(de: D, ret: Event) -> Result<Event, D::Error>
-            where
-                D: Deserializer<'de>,
-            {
-                serde_json::Value::deserialize(de).and_then(|v| {
-                    if v == serde_json::Value::Null {
-                        Ok(ret)
-                    } else {
-                        Err(D::Error::invalid_value(
-                            Unexpected::Other("non-null payload"),
-                            &"null",
-                        ))
-                    }
-                })
+            fn decode_null<'a>(d: RawPayload<'_>, ret: Event<'a>) -> anyhow::Result<Event<'a>> {
+                match d.decode()? {
+                    serde_json::Value::Null => Ok(ret),
+                    _ => bail!("invalid value: non-null payload, expected null"),
+                }
             }
 
-            let mut de = serde_json::Deserializer::from_str(raw.d.get());
-
-            const OP_DISPATCH: u8 = 0;
-            const OP_RECONNECT: u8 = 7;
-            const OP_INVALID_SESSION: u8 = 9;
-            const OP_HELLO: u8 = 10;
-            const OP_HEARTBEAT_ACK: u8 = 11;
-
-            let ret = match raw.op {
-                OP_RECONNECT => deserialize_null(&mut de, Event::Reconnect),
-                OP_INVALID_SESSION => bool::deserialize(&mut de).map(Event::InvalidSession),
-                OP_HELLO => Hello::deserialize(&mut de).map(Event::Hello),
-                OP_HEARTBEAT_ACK => deserialize_null(&mut de, Event::HeartbeatAck),
+            let d = raw.d;
+            match raw.op {
+                OP_RECONNECT => decode_null(d, Event::Reconnect),
+                OP_INVALID_SESSION => d.decode().map(Event::InvalidSession),
+                OP_HELLO => d.decode().map(Event::Hello),
+                OP_HEARTBEAT_ACK => decode_null(d, Event::HeartbeatAck),
+                OP_HEARTBEAT => d.decode::<IgnoredAny>().map(|_| Event::Heartbeat),
                 OP_DISPATCH => get_dispatch(
-                    &mut de,
-                    raw.t.ok_or_else(|| serde_json::Error::missing_field("t"))?,
-                    raw.s.ok_or_else(|| serde_json::Error::missing_field("s"))?,
+                    d,
+                    raw.t.ok_or_else(|| anyhow!("missing field `t`"))?,
+                    raw.s.ok_or_else(|| anyhow!("missing field `s`"))?,
                 )
                 .map(Event::Dispatch),
-                n => Err(serde_json::Error::invalid_value(
-                    Unexpected::Unsigned(n as u64),
-                    &"valid gateway event code (0-11)",
-                )),
-            }?;
-            de.end()?;
-            Ok(ret)
+                n => bail!(
+                    "invalid value: integer `{}`, expected valid gateway event code (0-11)",
+                    n
+                ),
+            }
         }
     }
 }
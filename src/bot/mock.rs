@@ -0,0 +1,500 @@
+//! Local stand-ins for the Discord gateway and REST API, for driving a `Bot` end to end in tests.
+//!
+//! A test binds a [`MockGateway`] and a [`MockRest`], builds a `Bot` from [`connection_config`],
+//! and then uses [`run_scripted`] to run the bot alongside a script that plays the server's side
+//! of the conversation.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, ensure, Result};
+use async_io::{Async, Timer};
+use async_tungstenite::tungstenite::handshake::server::Request as HandshakeRequest;
+use async_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use async_tungstenite::tungstenite::protocol::CloseFrame;
+use async_tungstenite::tungstenite::Message;
+use async_tungstenite::WebSocketStream;
+use flate2::write::ZlibEncoder;
+use flate2::{Compress, FlushCompress};
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use serde_json::{json, Value};
+
+use crate::bot::etf;
+use crate::bot::reconnect::ReconnectPolicy;
+use crate::bot::{AsyncDispatchHandler, Bot, ConnectionConfig};
+
+/// How long a scripted test may run before it is considered hung.
+const SCRIPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Builds a connection config that points the REST API at the mock, which in turn points the bot
+/// at the mock gateway, and retries failed sessions without noticeable delay.
+pub fn connection_config(rest: &MockRest) -> ConnectionConfig {
+    ConnectionConfig {
+        api_root: rest.root.clone(),
+        reconnect: ReconnectPolicy {
+            initial_delay_ms: 10,
+            max_delay_ms: 100,
+            ..ReconnectPolicy::default()
+        },
+        // the mock gateway does not rate limit identifies
+        identify_interval_ms: 100,
+        ..ConnectionConfig::default()
+    }
+}
+
+/// Runs `bot` with `handler` until `script` completes.
+///
+/// Fails if the script fails, if the bot stops first, or if the script does not finish
+/// within a few seconds.
+pub fn run_scripted(
+    bot: &Bot,
+    handler: impl AsyncDispatchHandler,
+    script: impl Future<Output = Result<()>>,
+) -> Result<()> {
+    async_io::block_on(async {
+        let bot = bot.run_async(handler).boxed_local();
+        let script = future::select(
+            script.boxed_local(),
+            Timer::after(SCRIPT_TIMEOUT).boxed_local(),
+        )
+        .map(|either| match either {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(anyhow!("script timed out")),
+        });
+
+        match future::select(bot, script.boxed_local()).await {
+            Either::Left((result, _)) => match result {
+                Ok(()) => bail!("bot stopped before the script finished"),
+                Err(e) => Err(e.context("bot stopped before the script finished")),
+            },
+            Either::Right((result, _)) => result,
+        }
+    })
+}
+
+/// Runs `bot` with `handler` alongside `script` until the bot stops, and returns its result.
+///
+/// Fails if the script fails, or if the bot does not stop within a few seconds.
+pub fn run_until_stopped(
+    bot: &Bot,
+    handler: impl AsyncDispatchHandler,
+    script: impl Future<Output = Result<()>>,
+) -> Result<Result<()>> {
+    async_io::block_on(async {
+        let both = future::join(bot.run_async(handler), script);
+        match future::select(both.boxed_local(), Timer::after(SCRIPT_TIMEOUT)).await {
+            Either::Left(((bot_result, script_result), _)) => script_result.map(|_| bot_result),
+            Either::Right(_) => Err(anyhow!("bot did not stop")),
+        }
+    })
+}
+
+/// A WebSocket server speaking the server side of the gateway protocol.
+pub struct MockGateway {
+    listener: Async<TcpListener>,
+    pub url: String,
+}
+
+impl MockGateway {
+    pub fn bind() -> Result<Self> {
+        let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 0))?;
+        let url = format!("ws://{}", listener.get_ref().local_addr()?);
+        Ok(MockGateway { listener, url })
+    }
+
+    /// Waits for the bot to open a connection.
+    ///
+    /// Events sent over it are compressed if the bot asked for `compress=zlib-stream`, and encoded
+    /// as ETF if it asked for `encoding=etf`.
+    pub async fn accept(&self) -> Result<MockConnection> {
+        let (stream, _) = self.listener.accept().await?;
+        let mut query = HashMap::new();
+        let ws =
+            async_tungstenite::accept_hdr_async(stream, |request: &HandshakeRequest, response| {
+                query.extend(
+                    url::form_urlencoded::parse(request.uri().query().unwrap_or("").as_bytes())
+                        .into_owned(),
+                );
+                Ok(response)
+            })
+            .await?;
+        let compress = match query.get("compress").map(String::as_str) {
+            Some("zlib-stream") => Some(Compress::new(flate2::Compression::default(), true)),
+            _ => None,
+        };
+        let etf = query.get("encoding").map(String::as_str) == Some("etf");
+        Ok(MockConnection {
+            ws,
+            query,
+            compress,
+            etf,
+            seq: 0,
+            auto_ack: true,
+            compress_payloads: false,
+        })
+    }
+}
+
+/// One gateway connection, seen from the server's side.
+pub struct MockConnection {
+    ws: WebSocketStream<Async<TcpStream>>,
+    /// Query parameters of the URL the bot connected to.
+    pub query: HashMap<String, String>,
+    compress: Option<Compress>,
+    etf: bool,
+    /// Sequence number of the last dispatch sent.
+    pub seq: usize,
+    /// Whether heartbeats are acknowledged automatically while waiting for other commands.
+    pub auto_ack: bool,
+    /// Whether events are compressed one by one, as for bots that identified with `compress`.
+    pub compress_payloads: bool,
+}
+
+impl MockConnection {
+    pub async fn send_event(&mut self, op: u8, t: Option<&str>, d: Value) -> Result<()> {
+        let s = if t.is_some() { Some(self.seq) } else { None };
+        self.send_raw(json!({ "op": op, "t": t, "s": s, "d": d }).to_string())
+            .await
+    }
+
+    /// Sends `text` as one event. On compressed connections, it is split over two binary frames.
+    pub async fn send_raw(&mut self, text: String) -> Result<()> {
+        let data = if self.etf {
+            etf::to_vec(&serde_json::from_str::<Value>(&text)?)?
+        } else {
+            text.into_bytes()
+        };
+        match &mut self.compress {
+            None if self.compress_payloads => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&data)?;
+                self.ws.send(Message::Binary(encoder.finish()?)).await?
+            }
+            Some(compress) => {
+                let mut compressed = Vec::with_capacity(data.len() + 64);
+                compress.compress_vec(&data, &mut compressed, FlushCompress::Sync)?;
+                let second = compressed.split_off(compressed.len() / 2);
+                self.ws.send(Message::Binary(compressed)).await?;
+                self.ws.send(Message::Binary(second)).await?;
+            }
+            None if self.etf => self.ws.send(Message::Binary(data)).await?,
+            None => {
+                self.ws
+                    .send(Message::Text(String::from_utf8(data)?))
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a dispatch with the next sequence number.
+    pub async fn dispatch(&mut self, t: &str, d: Value) -> Result<()> {
+        self.seq += 1;
+        self.send_event(0, Some(t), d).await
+    }
+
+    pub async fn hello(&mut self, heartbeat_interval: u64) -> Result<()> {
+        self.send_event(
+            10,
+            None,
+            json!({ "heartbeat_interval": heartbeat_interval }),
+        )
+        .await
+    }
+
+    pub async fn ready(&mut self, session_id: &str, user_id: u64) -> Result<()> {
+        self.dispatch(
+            "READY",
+            json!({ "session_id": session_id, "user": user(user_id, "bot") }),
+        )
+        .await
+    }
+
+    pub async fn heartbeat_ack(&mut self) -> Result<()> {
+        self.send_event(11, None, Value::Null).await
+    }
+
+    pub async fn reconnect(&mut self) -> Result<()> {
+        self.send_event(7, None, Value::Null).await
+    }
+
+    pub async fn invalid_session(&mut self, resumable: bool) -> Result<()> {
+        self.send_event(9, None, json!(resumable)).await
+    }
+
+    /// Expects the bot to identify, then says hello and sends `READY`. Returns the identify payload.
+    pub async fn handshake(&mut self, session_id: &str, user_id: u64) -> Result<Value> {
+        let identify = self.expect_command(2).await?;
+        self.hello(45000).await?;
+        self.ready(session_id, user_id).await?;
+        Ok(identify)
+    }
+
+    /// Waits for the next command from the bot and returns it whole.
+    ///
+    /// Heartbeats are answered and skipped while `auto_ack` is set.
+    pub async fn next_command(&mut self) -> Result<Value> {
+        loop {
+            let command: Value = match self.ws.next().await {
+                Some(Ok(Message::Text(s))) => serde_json::from_str(&s)?,
+                Some(Ok(Message::Binary(data))) => etf::from_slice(&data)?,
+                Some(Ok(Message::Close(frame))) => bail!("connection closed by bot: {:?}", frame),
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(e.into()),
+                None => bail!("connection closed by bot"),
+            };
+            if self.auto_ack && command["op"] == 1 {
+                self.heartbeat_ack().await?;
+            } else {
+                break Ok(command);
+            }
+        }
+    }
+
+    /// Waits for the next command from the bot, expecting it to have opcode `op`. Returns its payload.
+    pub async fn expect_command(&mut self, op: u8) -> Result<Value> {
+        let mut command = self.next_command().await?;
+        ensure!(
+            command["op"] == op,
+            "expected command with op {}, got {}",
+            op,
+            command
+        );
+        Ok(command["d"].take())
+    }
+
+    /// Waits for the bot to close the connection, ignoring anything it sends before.
+    pub async fn expect_closed(mut self) -> Result<Option<CloseFrame<'static>>> {
+        let mut frame = None;
+        while let Some(message) = self.ws.next().await {
+            match message {
+                Ok(Message::Close(f)) => frame = f,
+                Ok(_) => (),
+                Err(_) => break,
+            }
+        }
+        Ok(frame)
+    }
+
+    /// Closes the connection from the server's side.
+    pub async fn close(mut self, code: u16, reason: &str) -> Result<()> {
+        self.ws
+            .close(Some(CloseFrame {
+                code: CloseCode::from(code),
+                reason: reason.to_string().into(),
+            }))
+            .await?;
+        self.expect_closed().await.map(|_| ())
+    }
+}
+
+/// A `User` object as it appears in gateway payloads.
+pub fn user(id: u64, name: &str) -> Value {
+    json!({ "id": id.to_string(), "username": name, "discriminator": "0001" })
+}
+
+/// A `MESSAGE_CREATE` payload.
+pub fn message(id: u64, channel_id: u64, author_id: u64, content: &str) -> Value {
+    json!({
+        "id": id.to_string(),
+        "channel_id": channel_id.to_string(),
+        "content": content,
+        "timestamp": "2020-10-10T12:00:00.000000+00:00",
+        "author": user(author_id, "someone"),
+        "mentions": [],
+    })
+}
+
+/// A request received by [`MockRest`]. The path is relative to the API root.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn body_str(&self) -> &str {
+        std::str::from_utf8(&self.body).expect("request body should be utf-8")
+    }
+
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).expect("request body should be json")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json(status: u16, body: Value) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+type Responder = dyn Fn(&Request) -> Response + Send + Sync;
+
+/// An HTTP server standing in for the REST API.
+///
+/// Every request is recorded and answered by the responder, except for `GET /gateway/bot`,
+/// which is answered with the URL of the gateway given to [`MockRest::start`], one recommended
+/// shard and plenty of session starts.
+pub struct MockRest {
+    pub root: String,
+    requests: mpsc::UnboundedReceiver<Request>,
+}
+
+impl MockRest {
+    /// Starts a server answering every request with an empty JSON object.
+    pub fn start(gateway_url: &str) -> Result<Self> {
+        Self::with_responder(gateway_url, |_| Response::json(200, json!({})))
+    }
+
+    pub fn with_responder(
+        gateway_url: &str,
+        responder: impl Fn(&Request) -> Response + Send + Sync + 'static,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", 0))?;
+        let root = format!("http://{}/api", listener.local_addr()?);
+        let (sender, requests) = mpsc::unbounded();
+        let responder: Arc<Responder> = Arc::new(responder);
+        let gateway_url = gateway_url.to_string();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(_) => break,
+                };
+                let sender = sender.clone();
+                let responder = responder.clone();
+                let gateway_url = gateway_url.clone();
+                thread::spawn(move || serve(stream, &gateway_url, &*responder, sender));
+            }
+        });
+
+        Ok(MockRest { root, requests })
+    }
+
+    /// Waits for the next request.
+    pub async fn next_request(&mut self) -> Result<Request> {
+        self.requests
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("REST server stopped"))
+    }
+
+    /// Waits for the next request with the given method, skipping any others.
+    pub async fn expect_request(&mut self, method: &str) -> Result<Request> {
+        loop {
+            let request = self.next_request().await?;
+            if request.method == method {
+                break Ok(request);
+            }
+        }
+    }
+}
+
+fn serve(
+    stream: TcpStream,
+    gateway_url: &str,
+    responder: &Responder,
+    requests: mpsc::UnboundedSender<Request>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let mut request_line = line.split_whitespace();
+        let method = request_line.next().unwrap_or_default().to_string();
+        let target = request_line.next().unwrap_or_default();
+        let path = target.strip_prefix("/api").unwrap_or(target).to_string();
+
+        let mut headers = HashMap::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            match line.trim_end().split_once(':') {
+                Some((name, value)) => {
+                    headers.insert(name.to_ascii_lowercase(), value.trim().to_string());
+                }
+                None => break,
+            }
+        }
+
+        if headers.get("expect").map(String::as_str) == Some("100-continue") {
+            writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        }
+        let length = headers
+            .get("content-length")
+            .and_then(|l| l.parse().ok())
+            .unwrap_or(0);
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+
+        let request = Request {
+            method,
+            path,
+            headers,
+            body,
+        };
+        let response = if request.method == "GET" && request.path == "/gateway/bot" {
+            Response::json(
+                200,
+                json!({
+                    "url": gateway_url,
+                    "shards": 1,
+                    "session_start_limit": {
+                        "total": 1000,
+                        "remaining": 1000,
+                        "reset_after": 0,
+                        "max_concurrency": 16,
+                    },
+                }),
+            )
+        } else {
+            let response = responder(&request);
+            let _ = requests.unbounded_send(request);
+            response
+        };
+
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+            response.status,
+            http::StatusCode::from_u16(response.status)
+                .ok()
+                .and_then(|s| s.canonical_reason())
+                .unwrap_or("Unknown"),
+            response.body.len()
+        )?;
+        for (name, value) in &response.headers {
+            write!(writer, "{}: {}\r\n", name, value)?;
+        }
+        write!(writer, "\r\n{}", response.body)?;
+        writer.flush()?;
+    }
+}
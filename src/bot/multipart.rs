@@ -0,0 +1,95 @@
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use rand::Rng;
+
+/// A file to upload along with a message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Attachment {
+    pub filename: String,
+    pub data: Vec<u8>,
+}
+
+impl Attachment {
+    pub fn from_bytes(filename: impl Into<String>, data: impl Into<Vec<u8>>) -> Self {
+        Attachment {
+            filename: filename.into(),
+            data: data.into(),
+        }
+    }
+
+    /// Reads the file at `path`, to be uploaded under the same name.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let filename = path
+            .file_name()
+            .ok_or_else(|| anyhow!("{} is not a file", path.display()))?
+            .to_string_lossy()
+            .into_owned();
+        Ok(Attachment {
+            filename,
+            data: std::fs::read(path)?,
+        })
+    }
+}
+
+/// A `multipart/form-data` body holding the JSON payload of a request and the files uploaded
+/// with it.
+pub(crate) struct FormData {
+    boundary: String,
+    body: Vec<u8>,
+}
+
+impl FormData {
+    pub fn new(payload_json: &str, attachments: &[Attachment]) -> Self {
+        let mut rng = rand::thread_rng();
+        let boundary = format!("{:016x}{:016x}", rng.gen::<u64>(), rng.gen::<u64>());
+        let mut form = FormData {
+            boundary,
+            body: Vec::new(),
+        };
+        form.part(
+            "Content-Disposition: form-data; name=\"payload_json\"\r\n\
+             Content-Type: application/json",
+            payload_json.as_bytes(),
+        );
+        for (i, attachment) in attachments.iter().enumerate() {
+            let header = format!(
+                "Content-Disposition: form-data; name=\"file{}\"; filename=\"{}\"\r\n\
+                 Content-Type: application/octet-stream",
+                i,
+                escape_filename(&attachment.filename)
+            );
+            form.part(&header, &attachment.data);
+        }
+        // writing to a Vec cannot fail
+        let _ = write!(form.body, "--{}--\r\n", form.boundary);
+        form
+    }
+
+    fn part(&mut self, header: &str, data: &[u8]) {
+        let _ = write!(self.body, "--{}\r\n{}\r\n\r\n", self.boundary, header);
+        self.body.extend_from_slice(data);
+        self.body.extend_from_slice(b"\r\n");
+    }
+
+    pub fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    pub fn into_body(self) -> Vec<u8> {
+        self.body
+    }
+}
+
+/// Keeps a filename from ending its quoted string or header line early.
+fn escape_filename(filename: &str) -> String {
+    filename
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' | '\r' | '\n' => '_',
+            c => c,
+        })
+        .collect()
+}
@@ -0,0 +1,230 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use futures::lock::Mutex;
+use http::HeaderMap;
+
+/// Number of commands the gateway accepts per connection in every `COMMAND_WINDOW`.
+const COMMAND_LIMIT: f64 = 120.0;
+const COMMAND_WINDOW: Duration = Duration::from_secs(60);
+/// Commands of the limit only heartbeats may use, so that other commands never hold them up.
+const HEARTBEAT_RESERVE: f64 = 5.0;
+
+/// Largest payload the gateway accepts, in bytes.
+pub const MAX_PAYLOAD_SIZE: usize = 4096;
+
+/// Error for a command too large for the gateway to accept. Sending it would close the
+/// connection, so it is dropped instead.
+#[derive(Debug)]
+pub struct PayloadTooLarge {
+    pub op: u8,
+    pub size: usize,
+}
+
+impl std::fmt::Display for PayloadTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "command with op {} is {} bytes, more than the {} the gateway accepts",
+            self.op, self.size, MAX_PAYLOAD_SIZE
+        )
+    }
+}
+
+impl std::error::Error for PayloadTooLarge {}
+
+/// A token bucket keeping the commands sent on one gateway connection within the gateway's limit.
+pub(crate) struct CommandLimiter {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl CommandLimiter {
+    pub fn new(now: Instant) -> Self {
+        CommandLimiter {
+            tokens: COMMAND_LIMIT,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * Self::rate()).min(COMMAND_LIMIT);
+        self.updated = now;
+    }
+
+    /// Tokens regained per second.
+    fn rate() -> f64 {
+        COMMAND_LIMIT / COMMAND_WINDOW.as_secs_f64()
+    }
+
+    fn delay_until(&self, tokens: f64) -> Option<Duration> {
+        if self.tokens >= tokens {
+            None
+        } else {
+            Some(Duration::from_secs_f64(
+                (tokens - self.tokens) / Self::rate(),
+            ))
+        }
+    }
+
+    /// How long until a command other than a heartbeat may be sent, if it may not be sent now.
+    pub fn command_delay(&mut self, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        self.delay_until(HEARTBEAT_RESERVE + 1.0)
+    }
+
+    /// Takes a token for a command, and returns how long to wait before sending it.
+    pub fn reserve(&mut self, heartbeat: bool, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        let floor = if heartbeat { 0.0 } else { HEARTBEAT_RESERVE };
+        let delay = self.delay_until(floor + 1.0);
+        self.tokens -= 1.0;
+        delay
+    }
+}
+
+/// What a REST request is rate limited by: its method and path, with the IDs Discord does not
+/// tell buckets apart by left out.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Route {
+    /// Method and path, with IDs replaced, like `PUT /channels/:major/messages/:id/reactions/:emoji/@me`.
+    template: String,
+    /// ID of the channel, guild or webhook the request is about, if any. Requests about
+    /// different ones are limited separately, even in the same bucket.
+    major: String,
+}
+
+impl Route {
+    pub fn new(method: &str, endpoint: &str) -> Self {
+        let path = endpoint.split('?').next().unwrap_or_default();
+        let mut template = String::from(method);
+        template.push(' ');
+        let mut major = String::new();
+        let mut previous = "";
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            template.push('/');
+            template.push_str(match previous {
+                "channels" | "guilds" | "webhooks" if major.is_empty() => {
+                    major.push_str(segment);
+                    ":major"
+                }
+                "reactions" => ":emoji",
+                _ if segment.bytes().all(|b| b.is_ascii_digit()) => ":id",
+                _ => segment,
+            });
+            previous = segment;
+        }
+        Route { template, major }
+    }
+
+    /// Identifies the route's bucket until the bucket's hash is known.
+    fn key(&self) -> String {
+        format!("{} {}", self.template, self.major)
+    }
+}
+
+impl Display for Route {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.major.is_empty() {
+            f.write_str(&self.template)
+        } else {
+            write!(f, "{} ({})", self.template, self.major)
+        }
+    }
+}
+
+/// Limits of a bucket, as of the last response to a request in it.
+struct Bucket {
+    remaining: u64,
+    reset: Instant,
+}
+
+/// Keeps REST requests within the limits Discord reports in the headers of its responses.
+///
+/// Buckets are told apart by `X-RateLimit-Bucket` and the route's major ID. Requests on the
+/// same route are sent one at a time, so that each one sees the limits left by the one before it.
+#[derive(Default)]
+pub(crate) struct RestLimiter {
+    /// Bucket hash of every route template seen so far.
+    hashes: RefCell<HashMap<String, String>>,
+    buckets: RefCell<HashMap<String, Bucket>>,
+    turns: RefCell<HashMap<String, Rc<Mutex<()>>>>,
+    /// When the global rate limit hit last ends.
+    global_reset: Cell<Option<Instant>>,
+}
+
+impl RestLimiter {
+    fn bucket_key(&self, route: &Route) -> String {
+        match self.hashes.borrow().get(&route.template) {
+            Some(hash) => format!("{} {}", hash, route.major),
+            None => route.key(),
+        }
+    }
+
+    /// A lock to hold while sending a request on `route`.
+    pub fn turn(&self, route: &Route) -> Rc<Mutex<()>> {
+        self.turns
+            .borrow_mut()
+            .entry(route.key())
+            .or_default()
+            .clone()
+    }
+
+    /// How long until a request may be sent on `route`, if it may not be sent now.
+    pub fn delay(&self, route: &Route, now: Instant) -> Option<Duration> {
+        let global = self.global_reset.get().filter(|&reset| reset > now);
+        let bucket = self
+            .buckets
+            .borrow()
+            .get(&self.bucket_key(route))
+            .filter(|bucket| bucket.remaining == 0 && bucket.reset > now)
+            .map(|bucket| bucket.reset);
+        global.max(bucket).map(|reset| reset - now)
+    }
+
+    /// Takes note of the limits reported in the headers of a response to a request on `route`.
+    pub fn update(&self, route: &Route, headers: &HeaderMap, now: Instant) {
+        if let Some(hash) = header::<String>(headers, "X-RateLimit-Bucket") {
+            self.hashes
+                .borrow_mut()
+                .insert(route.template.clone(), hash);
+        }
+        let remaining = header(headers, "X-RateLimit-Remaining");
+        let reset_after = header(headers, "X-RateLimit-Reset-After");
+        if let (Some(remaining), Some(reset_after)) = (remaining, reset_after) {
+            let bucket = Bucket {
+                remaining,
+                reset: now + Duration::from_secs_f64(reset_after),
+            };
+            self.buckets
+                .borrow_mut()
+                .insert(self.bucket_key(route), bucket);
+        }
+    }
+
+    /// Holds back requests after one on `route` was rejected for hitting a rate limit: every
+    /// request if the limit was the global one, or those in the route's bucket otherwise.
+    pub fn limited(&self, route: &Route, retry_after: Duration, global: bool, now: Instant) {
+        let reset = now + retry_after;
+        if global {
+            self.global_reset.set(Some(reset));
+        } else {
+            let bucket = Bucket {
+                remaining: 0,
+                reset,
+            };
+            self.buckets
+                .borrow_mut()
+                .insert(self.bucket_key(route), bucket);
+        }
+    }
+}
+
+fn header<T: FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use anyhow::{Error, Result};
+use async_io::Timer;
+use rand::Rng;
+use serde::Deserialize;
+
+/// How the bot retries after losing its gateway session.
+///
+/// Delays grow exponentially from `initial_delay_ms` up to `max_delay_ms`, and are reset once a
+/// connection has stayed up for `stable_after_ms`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ReconnectPolicy {
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    /// Factor the delay grows by after every failed attempt.
+    pub multiplier: f64,
+    /// Fraction of every delay that is randomized, between 0 and 1.
+    pub jitter: f64,
+    pub stable_after_ms: u64,
+    /// Number of consecutive failed attempts after which the bot gives up. Unlimited if unset.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay_ms: 1000,
+            max_delay_ms: 5 * 60 * 1000,
+            multiplier: 2.0,
+            jitter: 0.5,
+            stable_after_ms: 60 * 1000,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn stable_after(&self) -> Duration {
+        Duration::from_millis(self.stable_after_ms)
+    }
+}
+
+/// Hands out the delays of a `ReconnectPolicy` one attempt at a time.
+pub(crate) struct Backoff<'a> {
+    policy: &'a ReconnectPolicy,
+    attempts: u32,
+}
+
+impl<'a> Backoff<'a> {
+    pub fn new(policy: &'a ReconnectPolicy) -> Self {
+        Backoff {
+            policy,
+            attempts: 0,
+        }
+    }
+
+    /// Delay before the next attempt, or `None` if the policy allows no more attempts.
+    pub fn next_delay(&mut self, rng: &mut impl Rng) -> Option<Duration> {
+        if self
+            .policy
+            .max_attempts
+            .map_or(false, |max| self.attempts >= max)
+        {
+            return None;
+        }
+        let base = (self.policy.max_delay_ms as f64).min(
+            self.policy.initial_delay_ms as f64 * self.policy.multiplier.powi(self.attempts as i32),
+        );
+        let jitter = self.policy.jitter.max(0.0).min(1.0);
+        self.attempts += 1;
+        Some(Duration::from_millis(
+            (base * (1.0 - jitter * rng.gen::<f64>())) as u64,
+        ))
+    }
+
+    /// Reports a failed attempt, then waits out the next delay.
+    ///
+    /// Fails with `e` if the policy allows no more attempts.
+    pub async fn wait_after(&mut self, e: Error) -> Result<()> {
+        for cause in e.chain() {
+            eprintln!("{}", cause);
+        }
+        match self.next_delay(&mut rand::thread_rng()) {
+            Some(delay) => {
+                eprintln!("starting a new session in {:.1}s", delay.as_secs_f64());
+                Timer::after(delay).await;
+                Ok(())
+            }
+            None => Err(e.context("giving up after too many failed sessions")),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
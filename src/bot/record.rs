@@ -0,0 +1,125 @@
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, LineWriter, Write};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use serde_json::Value;
+
+use super::client::Client;
+use super::message::event::*;
+use super::types::*;
+use super::AsyncDispatchHandler;
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Sent by the gateway.
+    In,
+    /// Sent by the bot.
+    Out,
+}
+
+/// One line of a recording: a gateway payload, as JSON whatever the connection's encoding.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecordedFrame {
+    pub time: DateTime<Utc>,
+    pub shard: ShardInfo,
+    pub direction: Direction,
+    pub payload: Box<RawValue>,
+}
+
+/// Writes the gateway traffic of every shard to a JSONL file, if one is configured.
+pub(crate) struct TrafficRecorder {
+    file: Option<RefCell<LineWriter<File>>>,
+}
+
+impl TrafficRecorder {
+    /// Starts recording to the end of the file at `path`. Nothing is recorded if `path` is unset
+    /// or the file cannot be opened.
+    pub fn open(path: Option<&str>) -> Self {
+        let file =
+            path.and_then(
+                |path| match OpenOptions::new().create(true).append(true).open(path) {
+                    Ok(file) => Some(RefCell::new(LineWriter::new(file))),
+                    Err(e) => {
+                        eprintln!("cannot record gateway traffic to {}: {}", path, e);
+                        None
+                    }
+                },
+            );
+        TrafficRecorder { file }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Records a payload. Tokens are left out, so that recordings can be passed around.
+    pub fn record(&self, shard: ShardInfo, direction: Direction, payload: &str) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let payload = match redact(payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                eprintln!("cannot record payload: {}", e);
+                return;
+            }
+        };
+        let frame = RecordedFrame {
+            time: Utc::now(),
+            shard,
+            direction,
+            payload,
+        };
+        let line = serde_json::to_string(&frame).expect("recorded frame serialization");
+        if let Err(e) = writeln!(file.borrow_mut(), "{}", line) {
+            eprintln!("cannot record payload: {}", e);
+        }
+    }
+}
+
+fn redact(payload: &str) -> Result<Box<RawValue>> {
+    let mut value: Value = serde_json::from_str(payload)?;
+    if let Some(token) = value.get_mut("d").and_then(|d| d.get_mut("token")) {
+        *token = Value::from("<redacted>");
+    }
+    Ok(RawValue::from_string(value.to_string())?)
+}
+
+/// Feeds the dispatches of a recording made with `record_file` to `handler`, one after the
+/// other, with a stub `Client`.
+pub fn replay(path: &str, handler: impl AsyncDispatchHandler) -> Result<()> {
+    let client = Client::stub();
+    let reader = BufReader::new(File::open(path)?);
+    async_io::block_on(async {
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let frame: RecordedFrame = serde_json::from_str(&line)
+                .with_context(|| format!("line {} is not a recorded frame", number + 1))?;
+            if frame.direction != Direction::In {
+                continue;
+            }
+            match serde_json::from_str::<Event>(frame.payload.get()) {
+                Ok(Event::Dispatch(d)) => {
+                    if let Err(e) = handler
+                        .handle_message(d.payload, frame.shard, &client)
+                        .await
+                    {
+                        eprintln!("{}", e);
+                    }
+                }
+                Ok(_) => (),
+                Err(e) => eprintln!("line {}: {}", number + 1, e),
+            }
+        }
+        Ok(())
+    })
+}
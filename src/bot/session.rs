@@ -0,0 +1,98 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::types::*;
+
+/// A gateway session of one shard, as kept on disk so it can be resumed after a restart.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SavedSession {
+    pub session_id: String,
+    pub seq: Sequence,
+    /// Where to connect to resume the session.
+    pub resume_url: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SessionFile {
+    shard_count: u64,
+    sessions: BTreeMap<u64, SavedSession>,
+}
+
+/// The sessions of every shard, along with the file they are kept in, if any.
+pub(crate) struct SessionStore {
+    path: Option<PathBuf>,
+    current: RefCell<SessionFile>,
+    /// Sessions found on disk at startup that have not been resumed yet.
+    saved: RefCell<BTreeMap<u64, SavedSession>>,
+}
+
+impl SessionStore {
+    /// Loads the sessions kept at `path`. Sessions of a different number of shards are dropped,
+    /// since events would be routed to other shards than the ones resuming them.
+    pub fn load(path: Option<&str>, shard_count: u64) -> Self {
+        let path = path.map(PathBuf::from);
+        let saved = match &path {
+            Some(path) if path.exists() => match Self::read(path) {
+                Ok(file) if file.shard_count == shard_count => file.sessions,
+                Ok(_) => {
+                    println!("shard count changed, not resuming saved sessions");
+                    BTreeMap::new()
+                }
+                Err(e) => {
+                    eprintln!("could not read saved sessions: {}", e);
+                    BTreeMap::new()
+                }
+            },
+            _ => BTreeMap::new(),
+        };
+        SessionStore {
+            path,
+            current: RefCell::new(SessionFile {
+                shard_count,
+                sessions: BTreeMap::new(),
+            }),
+            saved: RefCell::new(saved),
+        }
+    }
+
+    fn read(path: &Path) -> Result<SessionFile> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    /// Whether sessions are kept on disk.
+    pub fn is_persistent(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// Takes the session of `shard` found on disk at startup, if any.
+    pub fn take_saved(&self, shard: u64) -> Option<SavedSession> {
+        self.saved.borrow_mut().remove(&shard)
+    }
+
+    pub fn update(&self, shard: u64, session: SavedSession) {
+        self.current.borrow_mut().sessions.insert(shard, session);
+    }
+
+    /// Writes the current sessions to disk. The file is replaced in one go, so that it is never
+    /// left half written. Only `durable` saves wait for the file to reach the disk.
+    pub fn save(&self, durable: bool) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let temp = path.with_extension("tmp");
+        let mut file = File::create(&temp)?;
+        file.write_all(&serde_json::to_vec(&*self.current.borrow())?)?;
+        if durable {
+            file.sync_all()?;
+        }
+        fs::rename(&temp, path)?;
+        Ok(())
+    }
+}
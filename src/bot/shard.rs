@@ -0,0 +1,803 @@
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use async_io::Timer;
+use async_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use async_tungstenite::tungstenite::protocol::CloseFrame;
+use async_tungstenite::tungstenite::Message;
+use futures::channel::mpsc;
+use futures::stream::FuturesUnordered;
+use futures::{future, future::Either, prelude::*, select};
+use rand::Rng;
+use serde::Deserialize;
+
+use super::dispatch::{DispatchQueue, QueuedEvent, ReceivedEvent};
+use super::etf;
+use super::handle::ShardCommand;
+use super::inflate::{inflate_payload, ZlibStream};
+use super::message::{command::*, event::*};
+use super::ratelimit::{CommandLimiter, PayloadTooLarge, MAX_PAYLOAD_SIZE};
+use super::reconnect::Backoff;
+use super::record::{Direction, TrafficRecorder};
+use super::session::{SavedSession, SessionStore};
+use super::types::*;
+use super::{wait, AsyncDispatchHandler, Bot, Compression, Encoding, WebSocket};
+
+macro_rules! expect_message_or_bail {
+    ($shard:expr, $stream:expr, $user_pat:pat = $message_type:ident => $result:expr) => {{
+        match $shard.next_event($stream).await?.parse()? {
+            Event::$message_type($user_pat) => $result,
+            e => bail!(
+                "first message received was not a {} message, got discriminant {:?}",
+                stringify!($message_type),
+                std::mem::discriminant(&e)
+            ),
+        }
+    }};
+}
+
+#[derive(Deserialize)]
+struct BotGateway {
+    url: String,
+    shards: u64,
+    session_start_limit: SessionStartLimit,
+}
+
+#[derive(Deserialize)]
+struct SessionStartLimit {
+    remaining: u64,
+    reset_after: u64,
+    max_concurrency: u64,
+}
+
+/// How long handlers get to finish the dispatches already received when shutting down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A dispatch received by a shard, on its way to the handler.
+type ShardEvent = (ShardInfo, ReceivedEvent, Option<Id>);
+
+/// Runs one gateway connection per shard, and feeds the events of all of them to one handler.
+pub struct ShardManager<'b> {
+    bot: &'b Bot,
+    url: String,
+    count: u64,
+    max_concurrency: u64,
+    /// Earliest time each identify bucket may identify again.
+    identify_slots: RefCell<Vec<Instant>>,
+    sessions: SessionStore,
+    recorder: TrafficRecorder,
+}
+
+impl<'b> ShardManager<'b> {
+    /// Looks up where to connect and how many shards to run.
+    ///
+    /// Unless the bot's connection config names a gateway URL, this asks `gateway/bot`, and waits
+    /// for enough session starts to be left to start every shard.
+    pub async fn new(bot: &'b Bot) -> Result<ShardManager<'b>> {
+        if let Some(url) = &bot.connection.gateway_url {
+            let count = bot.connection.shards.unwrap_or(1);
+            return Ok(Self::with_gateway(bot, url.clone(), count, 1));
+        }
+
+        let gateway = bot
+            .client
+            .make_get_request::<BotGateway>("gateway/bot")
+            .await?
+            .get_response_owned()?;
+        let count = bot.connection.shards.unwrap_or(gateway.shards);
+        let limit = gateway.session_start_limit;
+        if limit.remaining < count {
+            println!(
+                "only {} session starts left, waiting {}s for them to reset",
+                limit.remaining,
+                limit.reset_after / 1000
+            );
+            Timer::after(Duration::from_millis(limit.reset_after)).await;
+        }
+        Ok(Self::with_gateway(
+            bot,
+            gateway.url,
+            count,
+            limit.max_concurrency,
+        ))
+    }
+
+    pub(crate) fn with_gateway(
+        bot: &'b Bot,
+        url: String,
+        count: u64,
+        max_concurrency: u64,
+    ) -> Self {
+        let max_concurrency = max_concurrency.max(1);
+        let count = count.max(1);
+        ShardManager {
+            bot,
+            url,
+            count,
+            max_concurrency,
+            identify_slots: RefCell::new(vec![Instant::now(); max_concurrency as usize]),
+            sessions: SessionStore::load(bot.connection.session_file.as_deref(), count),
+            recorder: TrafficRecorder::open(bot.connection.record_file.as_deref()),
+        }
+    }
+
+    pub fn shard_count(&self) -> u64 {
+        self.count
+    }
+
+    /// Runs every shard, passing the dispatches of all of them to `handler`.
+    ///
+    /// Only returns once a shard fails for good, or every shard is shut down and the dispatches
+    /// received until then are handled.
+    pub async fn run(&self, handler: impl AsyncDispatchHandler) -> Result<()> {
+        let (sender, receiver) = mpsc::unbounded();
+        let shards = (0..self.count).map(|id| {
+            Shard {
+                manager: self,
+                info: ShardInfo {
+                    id,
+                    count: self.count,
+                },
+                events: sender.clone(),
+                inflate: RefCell::new(None),
+                limiter: RefCell::new(CommandLimiter::new(Instant::now())),
+            }
+            .run(self.bot.handle.attach_shard(id))
+        });
+        let shards = future::try_join_all(shards);
+        drop(sender);
+
+        let dispatch = self.dispatch(receiver, handler).boxed_local();
+        match future::select(shards, dispatch).await {
+            Either::Left((Ok(_), dispatch)) => {
+                match future::select(dispatch, Timer::after(SHUTDOWN_TIMEOUT)).await {
+                    Either::Left((result, _)) => result,
+                    Either::Right(_) => {
+                        eprintln!("gave up waiting for handlers to finish");
+                        Ok(())
+                    }
+                }
+            }
+            Either::Left((Err(e), _)) => Err(e),
+            Either::Right((result, shards)) => {
+                result?;
+                shards.await.map(|_| ())
+            }
+        }
+    }
+
+    /// Handles dispatches as they arrive, running as many handlers side by side as the
+    /// connection's `DispatchOrdering` allows. Shards keep heartbeating meanwhile, since they
+    /// only ever queue dispatches here.
+    async fn dispatch(
+        &self,
+        mut events: mpsc::UnboundedReceiver<ShardEvent>,
+        handler: impl AsyncDispatchHandler,
+    ) -> Result<()> {
+        let mut queue = DispatchQueue::new(self.bot.connection.ordering);
+        let mut tasks = FuturesUnordered::new();
+        loop {
+            while let Some(event) = queue.next_ready() {
+                tasks.push(self.handle_event(&handler, event));
+            }
+            select! {
+                event = events.next() => match event {
+                    Some((shard, event, channel)) => queue.push(shard, event, channel),
+                    None if queue.is_idle() => break,
+                    None => (),
+                },
+                lane = tasks.select_next_some() => queue.finish(lane),
+                complete => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles one dispatch, and returns its lane once done.
+    async fn handle_event(
+        &self,
+        handler: &impl AsyncDispatchHandler,
+        event: QueuedEvent,
+    ) -> Option<Id> {
+        let result = match event.event.payload() {
+            Ok(payload) => {
+                handler
+                    .handle_message(payload, event.shard, &self.bot.client)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            eprintln!("{}", e);
+        }
+        event.lane
+    }
+
+    /// Waits until `shard` may identify, keeping to `max_concurrency` identifies every identify
+    /// interval.
+    async fn wait_for_identify(&self, shard: ShardInfo) {
+        let slot = self.reserve_identify(shard, Instant::now());
+        Timer::at(slot).await;
+    }
+
+    /// Reserves the next identify slot of `shard`'s bucket, and returns when it starts.
+    pub(crate) fn reserve_identify(&self, shard: ShardInfo, now: Instant) -> Instant {
+        let mut slots = self.identify_slots.borrow_mut();
+        let next = &mut slots[(shard.id % self.max_concurrency) as usize];
+        let slot = (*next).max(now);
+        *next = slot + self.bot.connection.identify_interval();
+        slot
+    }
+}
+
+/// A single gateway connection, and the session running on it.
+struct Shard<'a> {
+    manager: &'a ShardManager<'a>,
+    info: ShardInfo,
+    events: mpsc::UnboundedSender<ShardEvent>,
+    /// Inflate context of the current connection, once it has sent a compressed frame.
+    inflate: RefCell<Option<ZlibStream>>,
+    /// Commands left to the current connection under the gateway's rate limit.
+    limiter: RefCell<CommandLimiter>,
+}
+
+impl Shard<'_> {
+    fn bot(&self) -> &Bot {
+        self.manager.bot
+    }
+
+    async fn connect(&self) -> Result<WebSocket> {
+        self.connect_to(&self.manager.url).await
+    }
+
+    async fn connect_to(&self, url: &str) -> Result<WebSocket> {
+        let ws = self.bot().connect_to_gateway(url).await?;
+        *self.inflate.borrow_mut() = None;
+        *self.limiter.borrow_mut() = CommandLimiter::new(Instant::now());
+        Ok(ws)
+    }
+
+    /// The payload of a message, if it completes a gateway event. Events are recorded here, if
+    /// the connection config asks for it.
+    fn decode(&self, message: Message) -> Result<Option<Vec<u8>>> {
+        let data = self.decode_frame(message)?;
+        if let Some(data) = &data {
+            let recorder = &self.manager.recorder;
+            if recorder.is_recording() {
+                match self.bot().connection.encoding {
+                    Encoding::Json => {
+                        recorder.record(self.info, Direction::In, std::str::from_utf8(data)?)
+                    }
+                    // recordings are JSON whatever the encoding, so ETF is only re-encoded for them
+                    Encoding::Etf => {
+                        let value: serde_json::Value = etf::from_slice(data)?;
+                        recorder.record(self.info, Direction::In, &value.to_string())
+                    }
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    fn decode_frame(&self, message: Message) -> Result<Option<Vec<u8>>> {
+        let connection = &self.bot().connection;
+        let data = match message {
+            Message::Text(s) => s.into_bytes(),
+            Message::Binary(data) if connection.compression == Compression::ZlibStream => {
+                match self
+                    .inflate
+                    .borrow_mut()
+                    .get_or_insert_with(ZlibStream::new)
+                    .push(&data)?
+                {
+                    Some(data) => data,
+                    None => return Ok(None),
+                }
+            }
+            // payloads compressed one by one are complete zlib streams, unlike ETF ones
+            Message::Binary(data) if self.bot().options.compress && data.first() == Some(&0x78) => {
+                inflate_payload(&data)?
+            }
+            Message::Binary(data) => data,
+            _ => return Ok(None),
+        };
+        Ok(Some(data))
+    }
+
+    /// Waits for the next gateway event, failing if the connection is closed first.
+    async fn next_event(&self, ws: &mut WebSocket) -> Result<ReceivedEvent> {
+        let frame = self.next_frame(ws).await?;
+        Ok(ReceivedEvent::new(self.bot().connection.encoding, frame))
+    }
+
+    async fn next_frame(&self, ws: &mut WebSocket) -> Result<Vec<u8>> {
+        loop {
+            let message = match ws.next().await {
+                Some(message) => message?,
+                None => bail!("connection closed during the opening handshake"),
+            };
+            match message {
+                Message::Close(Some(frame)) => bail!(GatewayClosed {
+                    code: u16::from(frame.code).into(),
+                    reason: frame.reason.into_owned(),
+                }),
+                m @ Message::Text(_) | m @ Message::Binary(_) => {
+                    if let Some(data) = self.decode(m)? {
+                        return Ok(data);
+                    }
+                }
+                m => bail!(m),
+            }
+        }
+    }
+
+    /// Sends a command, once the gateway's rate limit allows. Commands too large for the gateway
+    /// fail with `PayloadTooLarge` without being sent.
+    async fn send<C: Command>(&self, ws: &mut WebSocket, command: C) -> Result<()> {
+        let command = CommandSerializer(command);
+        let message = match self.bot().connection.encoding {
+            Encoding::Json => Message::Text(serde_json::to_string(&command)?),
+            Encoding::Etf => Message::Binary(etf::to_vec(&command)?),
+        };
+        let size = message.len();
+        if size > MAX_PAYLOAD_SIZE {
+            bail!(PayloadTooLarge { op: C::OP, size });
+        }
+
+        let delay = self
+            .limiter
+            .borrow_mut()
+            .reserve(C::OP == Heartbeat::OP, Instant::now());
+        if let Some(delay) = delay {
+            println!("waiting {}ms for the gateway rate limit", delay.as_millis());
+            Timer::after(delay).await;
+        }
+        let recorder = &self.manager.recorder;
+        if recorder.is_recording() {
+            match &message {
+                Message::Text(json) => recorder.record(self.info, Direction::Out, json),
+                // recordings are JSON whatever the encoding
+                _ => recorder.record(self.info, Direction::Out, &serde_json::to_string(&command)?),
+            }
+        }
+        ws.send(message).await?;
+        Ok(())
+    }
+
+    /// Passes a dispatch on to collectors and to the handler, along with the parse of it that
+    /// the shard has already made.
+    fn forward(&self, event: &ReceivedEvent, payload: &DispatchPayload<'_>) {
+        self.bot()
+            .handle
+            .receive_dispatch(self.info, payload, event);
+        // the receiver only goes away when the manager stops running
+        let _ = self
+            .events
+            .unbounded_send((self.info, event.clone(), payload.channel_id()));
+    }
+
+    async fn identify(&self, ws: &mut WebSocket) -> Result<()> {
+        self.manager.wait_for_identify(self.info).await;
+        let options = &self.bot().options;
+        self.send(
+            ws,
+            Identify {
+                token: self.bot().auth.clone(),
+                properties: options.properties.clone(),
+                intents: self.bot().intents,
+                compress: Some(true).filter(|_| options.compress),
+                large_threshold: options.large_threshold,
+                shard: self.info,
+                presence: self.bot().handle.presence(),
+            },
+        )
+        .await
+    }
+
+    async fn opening_handshake(&self, ws: &mut WebSocket) -> Result<State> {
+        self.identify(ws).await?;
+
+        let heartbeat_interval =
+            expect_message_or_bail!(self, ws, h = Hello => h.heartbeat_interval);
+        let event = self.next_event(ws).await?;
+        let dispatch = match event.parse()? {
+            Event::Dispatch(dispatch) => dispatch,
+            e => bail!(
+                "first message received was not a Dispatch message, got discriminant {:?}",
+                std::mem::discriminant(&e)
+            ),
+        };
+        let ready = match &dispatch.payload {
+            DispatchPayload::Ready(ready) => ready,
+            payload => bail!(
+                "first dispatch received was not a Ready message, got discriminant {:?}",
+                std::mem::discriminant(payload)
+            ),
+        };
+        self.forward(&event, &dispatch.payload);
+
+        Ok(State {
+            seq: dispatch.seq,
+            heartbeat_interval,
+            session_id: String::from(ready.session_id),
+            resume_url: self.resume_url(ready),
+            heartbeat_acked: true,
+            heartbeat_sent: None,
+            resuming: false,
+            closed: None,
+            identify_after: None,
+        })
+    }
+
+    /// Connects to resume a session saved by an earlier run. If the session is no longer valid,
+    /// the gateway says so, and a new one is identified as usual.
+    async fn resume_saved(&self, saved: SavedSession) -> Result<(WebSocket, State)> {
+        println!("resuming saved session {}", saved.session_id);
+        let mut ws = self.connect_to(&saved.resume_url).await?;
+        self.send(
+            &mut ws,
+            Resume {
+                token: self.bot().auth.clone(),
+                session_id: saved.session_id.clone(),
+                seq: saved.seq,
+            },
+        )
+        .await?;
+        let heartbeat_interval =
+            expect_message_or_bail!(self, &mut ws, h = Hello => h.heartbeat_interval);
+        let state = State {
+            seq: saved.seq,
+            heartbeat_interval,
+            session_id: saved.session_id,
+            resume_url: saved.resume_url,
+            heartbeat_acked: true,
+            heartbeat_sent: None,
+            resuming: true,
+            closed: None,
+            identify_after: None,
+        };
+        Ok((ws, state))
+    }
+
+    fn resume_url(&self, ready: &Ready) -> String {
+        ready
+            .resume_gateway_url
+            .map_or_else(|| self.manager.url.clone(), String::from)
+    }
+
+    /// Keeps the session of `state` in the session store, and writes the store to disk. Saves
+    /// made every heartbeat are not `durable`, so that they do not wait for the disk each time.
+    fn save_session(&self, state: &State, durable: bool) {
+        let sessions = &self.manager.sessions;
+        if !sessions.is_persistent() {
+            return;
+        }
+        sessions.update(
+            self.info.id,
+            SavedSession {
+                session_id: state.session_id.clone(),
+                seq: state.seq,
+                resume_url: state.resume_url.clone(),
+            },
+        );
+        if let Err(e) = sessions.save(durable) {
+            eprintln!("could not save session: {}", e);
+        }
+    }
+
+    async fn reconnect(&self, ws: &mut WebSocket, state: &mut State) -> Result<()> {
+        *ws = self.connect_to(&state.resume_url).await?;
+        state.heartbeat_acked = true;
+        state.heartbeat_sent = None;
+        state.resuming = true;
+        self.send(
+            ws,
+            Resume {
+                token: self.bot().auth.clone(),
+                session_id: state.session_id.clone(),
+                seq: state.seq,
+            },
+        )
+        .await
+    }
+
+    async fn reidentify(&self, ws: &mut WebSocket, state: &mut State) -> Result<()> {
+        self.bot().handle.drop_member_requests(self.info.id);
+        *ws = self.connect().await?;
+        state.heartbeat_acked = true;
+        state.heartbeat_sent = None;
+        state.resuming = false;
+        self.identify(ws).await
+    }
+
+    /// Identifies a new session on the current connection, after the gateway invalidated the
+    /// last one.
+    async fn identify_again(&self, ws: &mut WebSocket) -> Result<()> {
+        self.bot().handle.drop_member_requests(self.info.id);
+        self.identify(ws).await
+    }
+
+    async fn heartbeat(&self, ws: &mut WebSocket, state: &mut State) -> Result<()> {
+        self.send(ws, Heartbeat(Some(state.seq))).await?;
+        state.heartbeat_acked = false;
+        state.heartbeat_sent = Some(Instant::now());
+        self.save_session(state, false);
+        Ok(())
+    }
+
+    async fn disconnect(&self, ws: &mut WebSocket) -> Result<()> {
+        ws.close(None).await?;
+        Ok(())
+    }
+
+    /// Closes the connection for good.
+    ///
+    /// Closing normally ends the session, so when sessions are kept to be resumed later, the
+    /// connection is closed as if for a restart instead.
+    async fn close(&self, ws: &mut WebSocket, state: &State) -> Result<()> {
+        println!("closing the connection of shard {}", self.info.id);
+        let code = if self.manager.sessions.is_persistent() {
+            self.save_session(state, true);
+            CloseCode::Restart
+        } else {
+            CloseCode::Normal
+        };
+        ws.close(Some(CloseFrame {
+            code,
+            reason: "".into(),
+        }))
+        .await?;
+        Ok(())
+    }
+
+    fn handle_dispatch(&self, state: &mut State, dispatch: &Dispatch<'_>) {
+        let seq = dispatch.seq;
+        match &dispatch.payload {
+            DispatchPayload::Ready(ready) => {
+                println!("new session started");
+                state.session_id = String::from(ready.session_id);
+                state.resume_url = self.resume_url(ready);
+            }
+            DispatchPayload::Resumed => {
+                println!("session resumed");
+                state.resuming = false;
+            }
+            _ if state.seq.0 + 1 != seq.0 => {
+                eprintln!("Sequence gap: previous = {} got = {}", state.seq.0, seq.0);
+            }
+            _ => (),
+        }
+        state.seq = seq;
+        // handled here rather than with the other dispatches, so that handlers waiting for
+        // members are not what keeps the members from arriving
+        if let DispatchPayload::GuildMembersChunk(chunk) = &dispatch.payload {
+            self.bot().handle.receive_member_chunk(chunk);
+        }
+    }
+
+    async fn handle_message(
+        &self,
+        ws: &mut WebSocket,
+        state: &mut State,
+        message: Message,
+    ) -> Result<()> {
+        if let Message::Close(Some(frame)) = &message {
+            let code = GatewayCloseCode::from(u16::from(frame.code));
+            println!("gateway closed the connection: {} ({})", code, frame.reason);
+            state.closed = Some(GatewayClosed {
+                code,
+                reason: frame.reason.to_string(),
+            });
+        }
+        if let Some(data) = self.decode(message)? {
+            let event = ReceivedEvent::new(self.bot().connection.encoding, data);
+            let parsed = match event.parse() {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return Ok(());
+                }
+            };
+            match parsed {
+                Event::Dispatch(dispatch) => {
+                    self.handle_dispatch(state, &dispatch);
+                    self.forward(&event, &dispatch.payload);
+                }
+                Event::HeartbeatAck => {
+                    println!("heartbeat acknowledged");
+                    state.heartbeat_acked = true;
+                    if let Some(sent) = state.heartbeat_sent.take() {
+                        self.bot()
+                            .handle
+                            .record_latency(self.info.id, sent.elapsed());
+                    }
+                }
+                Event::Heartbeat => {
+                    println!("heartbeat requested");
+                    self.heartbeat(ws, state).await?;
+                }
+                Event::Reconnect => {
+                    println!("disconnecting (reconnect received)");
+                    self.disconnect(ws).await?;
+                }
+                Event::InvalidSession(true) => {
+                    println!("disconnecting (invalid session, expected reconnect)");
+                    self.disconnect(ws).await?
+                }
+                Event::InvalidSession(false) => {
+                    if state.resuming {
+                        println!("resume failed, identifying with a new session");
+                        state.resuming = false;
+                    } else {
+                        println!("invalid session, identifying with a new session");
+                    }
+                    // discord asks for a random wait between 1 and 5 seconds before identifying again
+                    state.identify_after = Some(rand::thread_rng().gen_range(1000, 5000));
+                }
+                Event::Hello(hello) => {
+                    state.heartbeat_interval = hello.heartbeat_interval;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn run_command(&self, ws: &mut WebSocket, command: ShardCommand) -> Result<()> {
+        match command {
+            ShardCommand::UpdateStatus(presence) => self.send(ws, presence).await,
+            ShardCommand::RequestGuildMembers(request) => self.send(ws, request).await,
+            ShardCommand::Shutdown => unreachable!("shutdowns are handled by the session loop"),
+        }
+    }
+
+    async fn run_loop(
+        &self,
+        ws: &mut WebSocket,
+        mut state: State,
+        commands: &mut mpsc::UnboundedReceiver<ShardCommand>,
+    ) -> Result<()> {
+        let mut timer = wait(state.heartbeat_interval);
+        let mut identify_timer = future::Fuse::terminated();
+        loop {
+            if let Some(delay) = state.identify_after.take() {
+                identify_timer = wait(delay);
+            }
+            let mut ws_fut = ws.next().fuse();
+            // commands wait in the channel while the rate limit leaves only room for heartbeats
+            let delay = self.limiter.borrow_mut().command_delay(Instant::now());
+            let mut command_fut = async {
+                if let Some(delay) = delay {
+                    Timer::after(delay).await;
+                }
+                commands.next().await
+            }
+            .boxed_local()
+            .fuse();
+            select! {
+                command = command_fut => match command {
+                    Some(ShardCommand::Shutdown) => return self.close(ws, &state).await,
+                    Some(command) => match self.run_command(ws, command).await {
+                        Err(e) if e.is::<PayloadTooLarge>() => eprintln!("{}", e),
+                        result => result?,
+                    },
+                    None => (),
+                },
+                _ = timer => {
+                    if !state.heartbeat_acked {
+                        println!("disconnecting (heartbeat ack missed)");
+                        self.disconnect(ws).await?;
+                    } else {
+                        self.heartbeat(ws, &mut state).await?;
+                        timer = wait(state.heartbeat_interval);
+                    }
+                }
+                _ = identify_timer => self.identify_again(ws).await?,
+                next = ws_fut => {
+                    match next {
+                        Some(msg) => self.handle_message(ws, &mut state, msg?).await?,
+                        None => {
+                            match state.closed.take() {
+                                Some(closed) if closed.code.is_fatal() => bail!(closed),
+                                Some(closed) if closed.code.action() == CloseAction::Identify => {
+                                    self.reidentify(ws, &mut state).await?
+                                }
+                                _ => self.reconnect(ws, &mut state).await?,
+                            }
+                            timer = wait(state.heartbeat_interval);
+                            identify_timer = future::Fuse::terminated();
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    async fn run_session(
+        &self,
+        commands: &mut mpsc::UnboundedReceiver<ShardCommand>,
+    ) -> Result<()> {
+        let saved = match self.manager.sessions.take_saved(self.info.id) {
+            Some(saved) => match self.resume_saved(saved).await {
+                Ok(resumed) => Some(resumed),
+                Err(e) => {
+                    eprintln!("could not resume saved session: {}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+        let (mut ws, state) = match saved {
+            Some(resumed) => resumed,
+            None => {
+                let mut ws = self.connect().await?;
+                let state = self.opening_handshake(&mut ws).await?;
+                (ws, state)
+            }
+        };
+        self.run_loop(&mut ws, state, commands).await
+    }
+
+    /// Runs sessions one after the other, as laid out by the reconnect policy.
+    ///
+    /// `commands` are sent to the gateway whenever a session is running.
+    async fn run(self, mut commands: mpsc::UnboundedReceiver<ShardCommand>) -> Result<()> {
+        let policy = &self.bot().connection.reconnect;
+        let mut backoff = Backoff::new(policy);
+        loop {
+            if self.bot().handle.is_shutting_down() {
+                break Ok(());
+            }
+            let started = Instant::now();
+            let result = self.run_session(&mut commands).await;
+            // answers to member requests only ever arrive in the session they were sent in
+            self.bot().handle.drop_member_requests(self.info.id);
+            let e = match result {
+                Ok(()) => break Ok(()),
+                Err(e) => e,
+            };
+            if e.downcast_ref::<GatewayClosed>()
+                .map_or(false, |closed| closed.code.is_fatal())
+            {
+                break Err(e);
+            }
+            if started.elapsed() >= policy.stable_after() {
+                backoff.reset();
+            }
+            // commands other than shutting down are of no use without a session, and the
+            // presence is sent again when identifying anyway
+            let shutdown = async {
+                while let Some(command) = commands.next().await {
+                    match command {
+                        ShardCommand::Shutdown => break,
+                        ShardCommand::RequestGuildMembers(_) => {
+                            self.bot().handle.drop_member_requests(self.info.id)
+                        }
+                        ShardCommand::UpdateStatus(_) => (),
+                    }
+                }
+            };
+            match future::select(Box::pin(backoff.wait_after(e)), Box::pin(shutdown)).await {
+                Either::Left((result, _)) => result?,
+                Either::Right(_) => break Ok(()),
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    seq: Sequence,
+    heartbeat_interval: u64,
+    session_id: String,
+    /// Where to connect to resume the session.
+    resume_url: String,
+    heartbeat_acked: bool,
+    heartbeat_sent: Option<Instant>,
+    /// Whether a `Resume` was sent that has not been answered with `RESUMED` yet.
+    resuming: bool,
+    /// How the gateway closed the current connection, if it has.
+    closed: Option<GatewayClosed>,
+    /// Milliseconds to wait before identifying again, once the gateway invalidated the session.
+    identify_after: Option<u64>,
+}
@@ -0,0 +1,1534 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::time::Instant;
+
+use anyhow::Result;
+use chrono::Utc;
+use futures::channel::mpsc;
+use futures::prelude::*;
+use serde_json::{json, Value};
+
+use super::api_error::{ApiErrorCode, DiscordApiError};
+use super::client::{AllowedMentions, CreateMessage, EditMessage, MAX_ATTACHMENTS};
+use super::dispatch::ReceivedEvent;
+use super::embed::Embed;
+use super::message::command::*;
+use super::mock::*;
+use super::multipart::Attachment;
+use super::*;
+
+const TOKEN: &str = "test_token";
+
+fn describe(payload: &DispatchPayload) -> String {
+    match payload {
+        DispatchPayload::Ready(ready) => format!("READY {}", ready.session_id),
+        DispatchPayload::MessageCreate(message) => {
+            format!("MESSAGE_CREATE {}", message.content.as_str())
+        }
+        DispatchPayload::TypingStart(_) => String::from("TYPING_START"),
+        DispatchPayload::Resumed => String::from("RESUMED"),
+        DispatchPayload::GuildMembersChunk(chunk) => format!(
+            "GUILD_MEMBERS_CHUNK {}/{}",
+            chunk.chunk_index + 1,
+            chunk.chunk_count
+        ),
+        DispatchPayload::Unknown {
+            event_type, raw, ..
+        } => format!("{} {}", event_type, raw),
+    }
+}
+
+/// Reports every dispatch it receives as a short description.
+struct Recorder(mpsc::UnboundedSender<String>);
+
+impl AsyncDispatchHandler for Recorder {
+    fn handle_message<'a>(
+        &'a self,
+        payload: DispatchPayload<'a>,
+        _shard: ShardInfo,
+        _client: &'a Client,
+    ) -> AsyncDispatchFuture<'a> {
+        let _ = self.0.unbounded_send(describe(&payload));
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+/// Reports every dispatch it receives along with the shard it came from.
+struct ShardRecorder(mpsc::UnboundedSender<(u64, String)>);
+
+impl AsyncDispatchHandler for ShardRecorder {
+    fn handle_message<'a>(
+        &'a self,
+        payload: DispatchPayload<'a>,
+        shard: ShardInfo,
+        _client: &'a Client,
+    ) -> AsyncDispatchFuture<'a> {
+        let _ = self.0.unbounded_send((shard.id, describe(&payload)));
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+/// Reports the messages it receives once it is done with them. Messages saying `slow` take a
+/// while to be done with.
+struct SlowRecorder(mpsc::UnboundedSender<String>);
+
+impl AsyncDispatchHandler for SlowRecorder {
+    fn handle_message<'a>(
+        &'a self,
+        payload: DispatchPayload<'a>,
+        _shard: ShardInfo,
+        _client: &'a Client,
+    ) -> AsyncDispatchFuture<'a> {
+        Box::pin(async move {
+            if let DispatchPayload::MessageCreate(message) = payload {
+                if message.content.as_str() == "slow" {
+                    Timer::after(Duration::from_millis(300)).await;
+                }
+                let _ = self.0.unbounded_send(format!(
+                    "{} {}",
+                    message.channel_id,
+                    message.content.as_str()
+                ));
+            }
+            Ok(())
+        })
+    }
+}
+
+fn setup_with(
+    connection: impl FnOnce(&mut ConnectionConfig),
+) -> Result<(Bot, MockGateway, MockRest)> {
+    let gateway = MockGateway::bind()?;
+    let rest = MockRest::start(&gateway.url)?;
+    let mut config = connection_config(&rest);
+    connection(&mut config);
+    let bot = Bot::new(
+        TokenBuf::from(TOKEN),
+        Intent::GuildMessages.and(Intent::DirectMessages),
+        GatewayOptions::default(),
+        config,
+    );
+    Ok((bot, gateway, rest))
+}
+
+fn setup() -> Result<(Bot, MockGateway, MockRest)> {
+    setup_with(|_| ())
+}
+
+#[test]
+fn identifies_and_dispatches_events() -> Result<()> {
+    let (bot, gateway, _rest) = setup()?;
+    let (sender, mut events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        let identify = conn.handshake("session", 1).await?;
+        assert_eq!(identify["token"], TOKEN);
+        assert_eq!(identify["intents"], (1 << 9) | (1 << 12));
+        assert_eq!(identify["shard"], json!([0, 1]));
+        assert_eq!(events.next().await.unwrap(), "READY session");
+
+        conn.dispatch("MESSAGE_CREATE", message(10, 20, 30, "hello"))
+            .await?;
+        assert_eq!(events.next().await.unwrap(), "MESSAGE_CREATE hello");
+        Ok(())
+    })
+}
+
+#[test]
+fn passes_unknown_events_through() -> Result<()> {
+    let (bot, gateway, _rest) = setup()?;
+    let (sender, mut events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.handshake("session", 1).await?;
+        conn.dispatch("GUILD_CREATE", json!({ "id": "40" })).await?;
+        assert_eq!(events.next().await.unwrap(), "READY session");
+        assert_eq!(events.next().await.unwrap(), r#"GUILD_CREATE {"id":"40"}"#);
+        conn.reconnect().await?;
+        conn.expect_closed().await?;
+
+        let mut conn = gateway.accept().await?;
+        assert_eq!(conn.expect_command(6).await?["seq"], 2);
+        Ok(())
+    })
+}
+
+#[test]
+fn inflates_compressed_events() -> Result<()> {
+    let (bot, gateway, _rest) = setup_with(|config| config.compression = Compression::ZlibStream)?;
+    let (sender, mut events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        assert_eq!(conn.query["compress"], "zlib-stream");
+        assert_eq!(conn.query["encoding"], "json");
+        conn.handshake("session", 1).await?;
+        assert_eq!(events.next().await.unwrap(), "READY session");
+
+        // later events reuse the inflate context of the earlier ones
+        for content in &["hello", "hello again"] {
+            conn.dispatch("MESSAGE_CREATE", message(10, 20, 30, content))
+                .await?;
+            assert_eq!(
+                events.next().await.unwrap(),
+                format!("MESSAGE_CREATE {}", content)
+            );
+        }
+        Ok(())
+    })
+}
+
+#[test]
+fn identifies_with_gateway_options() -> Result<()> {
+    let gateway = MockGateway::bind()?;
+    let rest = MockRest::start(&gateway.url)?;
+    let options: GatewayOptions = serde_json::from_value(json!({
+        "properties": { "os": "plan9", "browser": "taco_bot", "device": "taco_bot" },
+        "large_threshold": 250,
+        "presence": { "status": "idle", "activities": [{ "name": "with fire", "type": 0 }] },
+        "compress": true,
+    }))?;
+    let bot = Bot::new(
+        TokenBuf::from(TOKEN),
+        Intent::GuildMessages.and(Intent::DirectMessages),
+        options,
+        connection_config(&rest),
+    );
+    let (sender, mut events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.compress_payloads = true;
+        let identify = conn.handshake("session", 1).await?;
+        assert_eq!(
+            identify["properties"],
+            json!({ "$os": "plan9", "$browser": "taco_bot", "$device": "taco_bot" })
+        );
+        assert_eq!(identify["large_threshold"], 250);
+        assert_eq!(identify["compress"], true);
+        assert_eq!(identify["presence"]["status"], "idle");
+        assert_eq!(identify["presence"]["activities"][0]["name"], "with fire");
+        assert_eq!(events.next().await.unwrap(), "READY session");
+
+        conn.dispatch("MESSAGE_CREATE", message(10, 20, 30, "hello"))
+            .await?;
+        assert_eq!(events.next().await.unwrap(), "MESSAGE_CREATE hello");
+        Ok(())
+    })
+}
+
+#[test]
+fn rejects_gateway_options_the_gateway_would() -> Result<()> {
+    let connection = ConnectionConfig::default();
+    let options = |value: Value| serde_json::from_value::<GatewayOptions>(value);
+
+    options(json!({ "large_threshold": 50 }))?.validate(&connection)?;
+    options(json!({ "large_threshold": 250 }))?.validate(&connection)?;
+    assert!(options(json!({ "large_threshold": 49 }))?
+        .validate(&connection)
+        .is_err());
+    assert!(options(json!({ "large_threshold": 251 }))?
+        .validate(&connection)
+        .is_err());
+
+    let zlib_stream = ConnectionConfig {
+        compression: Compression::ZlibStream,
+        ..ConnectionConfig::default()
+    };
+    options(json!({ "compress": true }))?.validate(&connection)?;
+    assert!(options(json!({ "compress": true }))?
+        .validate(&zlib_stream)
+        .is_err());
+
+    // streaming is not for bots
+    let streaming = json!({ "presence": { "activities": [{ "name": "a", "type": 1 }] } });
+    assert!(options(streaming).is_err());
+    Ok(())
+}
+
+#[test]
+fn speaks_etf() -> Result<()> {
+    let (bot, gateway, _rest) = setup_with(|config| {
+        config.encoding = Encoding::Etf;
+        config.compression = Compression::ZlibStream;
+    })?;
+    let (sender, mut events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        assert_eq!(conn.query["encoding"], "etf");
+        let identify = conn.handshake("session", 1).await?;
+        assert_eq!(identify["token"], TOKEN);
+        assert_eq!(identify["shard"], json!([0, 1]));
+        assert_eq!(identify["compress"], Value::Null);
+        assert_eq!(events.next().await.unwrap(), "READY session");
+
+        conn.dispatch("MESSAGE_CREATE", message(10, 20, 30, "hello"))
+            .await?;
+        assert_eq!(events.next().await.unwrap(), "MESSAGE_CREATE hello");
+        conn.auto_ack = false;
+        conn.send_event(1, None, Value::Null).await?;
+        assert_eq!(conn.expect_command(1).await?, json!(2));
+        Ok(())
+    })
+}
+
+#[test]
+fn decodes_etf_as_the_gateway_sends_it() -> Result<()> {
+    // %{op: 0, s: 42, t: :GUILD_UPDATE, d: %{id: 2^60, nonce: nil, ok: true, tags: []}}
+    let mut input = vec![131, 116, 0, 0, 0, 4];
+    input.extend(&[119, 2, b'o', b'p', 97, 0]);
+    input.extend(&[119, 1, b's', 98, 0, 0, 0, 42]);
+    input.extend(&[119, 1, b't', 119, 12]);
+    input.extend(b"GUILD_UPDATE");
+    input.extend(&[119, 1, b'd', 116, 0, 0, 0, 4]);
+    input.extend(&[119, 2, b'i', b'd', 110, 8, 0, 0, 0, 0, 0, 0, 0, 0, 16]);
+    input.extend(&[
+        100, 0, 5, b'n', b'o', b'n', b'c', b'e', 100, 0, 3, b'n', b'i', b'l',
+    ]);
+    input.extend(&[119, 2, b'o', b'k', 119, 4, b't', b'r', b'u', b'e']);
+    input.extend(&[109, 0, 0, 0, 4, b't', b'a', b'g', b's', 106]);
+
+    let event = ReceivedEvent::new(Encoding::Etf, input);
+    let dispatch = match event.parse()? {
+        Event::Dispatch(dispatch) => dispatch,
+        _ => panic!("not a dispatch"),
+    };
+    assert_eq!(dispatch.seq.0, 42);
+    let raw = match &dispatch.payload {
+        DispatchPayload::Unknown {
+            event_type: "GUILD_UPDATE",
+            channel_id: None,
+            raw,
+        } => raw,
+        payload => panic!("unexpected payload {:?}", payload),
+    };
+    let value: Value = serde_json::from_str(raw.get())?;
+    assert_eq!(
+        value,
+        json!({ "id": 1u64 << 60, "nonce": null, "ok": true, "tags": [] })
+    );
+
+    #[derive(serde::Deserialize)]
+    struct Payload<'a> {
+        id: Id,
+        nonce: Option<&'a str>,
+    }
+    let term = &[
+        116, 0, 0, 0, 2, 119, 2, b'i', b'd', 110, 8, 0, 0, 0, 0, 0, 0, 0, 0, 16, 119, 5, b'n',
+        b'o', b'n', b'c', b'e', 109, 0, 0, 0, 1, b'x',
+    ];
+    let payload: Payload = RawPayload::Etf(term).decode()?;
+    assert_eq!(payload.id, Id::from_str(&(1u64 << 60).to_string())?);
+    assert_eq!(payload.nonce, Some("x"));
+    Ok(())
+}
+
+#[test]
+fn decodes_etf_ids_of_every_size() -> Result<()> {
+    let small: Id = etf::from_term(&[97, 200])?;
+    assert_eq!(small.get(), 200);
+    let int: Id = etf::from_term(&[98, 0, 1, 0, 0])?;
+    assert_eq!(int.get(), 1 << 16);
+    let big: Id = etf::from_term(&[110, 8, 0, 0, 0, 0, 0, 0, 0, 0, 16])?;
+    assert_eq!(big.get(), 1 << 60);
+    assert!(etf::from_term::<Id>(&[98, 255, 255, 255, 255]).is_err());
+    Ok(())
+}
+
+#[test]
+fn etf_round_trips_commands() -> Result<()> {
+    let identify = CommandSerializer(Heartbeat(Some(Sequence(300))));
+    let encoded = etf::to_vec(&identify)?;
+    assert_eq!(
+        encoded,
+        vec![131, 116, 0, 0, 0, 2, 119, 2, b'o', b'p', 97, 1, 119, 1, b'd', 98, 0, 0, 1, 44]
+    );
+    let value: Value = etf::from_slice(&encoded)?;
+    assert_eq!(value, json!({ "op": 1, "d": 300 }));
+
+    let nested =
+        json!({ "big": u64::MAX, "neg": -5_000_000_000i64, "list": [1, "two", null], "empty": [] });
+    let value: Value = etf::from_slice(&etf::to_vec(&nested)?)?;
+    assert_eq!(value, nested);
+    Ok(())
+}
+
+#[test]
+fn runs_every_shard() -> Result<()> {
+    let (bot, gateway, _rest) = setup_with(|config| config.shards = Some(2))?;
+    let (sender, mut events) = mpsc::unbounded();
+    run_scripted(&bot, ShardRecorder(sender), async {
+        let mut first = gateway.accept().await?;
+        let mut second = gateway.accept().await?;
+        let first_shard = first.handshake("first", 1).await?["shard"].clone();
+        let second_shard = second.handshake("second", 1).await?["shard"].clone();
+        let mut shards = vec![first_shard, second_shard];
+        shards.sort_by_key(|shard| shard[0].as_u64());
+        assert_eq!(shards, vec![json!([0, 2]), json!([1, 2])]);
+
+        let ready = [events.next().await.unwrap(), events.next().await.unwrap()];
+        let first_id = ready.iter().find(|(_, e)| e == "READY first").unwrap().0;
+        let second_id = ready.iter().find(|(_, e)| e == "READY second").unwrap().0;
+        assert_ne!(first_id, second_id);
+
+        second
+            .dispatch("MESSAGE_CREATE", message(10, 20, 30, "hello"))
+            .await?;
+        assert_eq!(
+            events.next().await.unwrap(),
+            (second_id, String::from("MESSAGE_CREATE hello"))
+        );
+        first
+            .dispatch("MESSAGE_CREATE", message(11, 20, 30, "world"))
+            .await?;
+        assert_eq!(
+            events.next().await.unwrap(),
+            (first_id, String::from("MESSAGE_CREATE world"))
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn identifies_within_concurrency_limit() -> Result<()> {
+    // nothing waits for the slots here, so the interval discord requires can be kept
+    let (bot, gateway, _rest) = setup_with(|config| {
+        config.identify_interval_ms = ConnectionConfig::default().identify_interval_ms
+    })?;
+    let manager = shard::ShardManager::with_gateway(&bot, gateway.url.clone(), 4, 2);
+    let shard = |id| ShardInfo { id, count: 4 };
+    let now = Instant::now();
+    let interval = Duration::from_secs(5);
+    assert_eq!(manager.reserve_identify(shard(0), now), now);
+    assert_eq!(manager.reserve_identify(shard(1), now), now);
+    assert_eq!(manager.reserve_identify(shard(2), now), now + interval);
+    assert_eq!(manager.reserve_identify(shard(3), now), now + interval);
+    assert_eq!(manager.reserve_identify(shard(0), now), now + interval * 2);
+    let later = now + interval * 10;
+    assert_eq!(manager.reserve_identify(shard(1), later), later);
+    Ok(())
+}
+
+#[test]
+fn heartbeats_carry_last_sequence() -> Result<()> {
+    let (bot, gateway, _rest) = setup()?;
+    let (sender, _events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.auto_ack = false;
+        conn.expect_command(2).await?;
+        conn.hello(50).await?;
+        conn.ready("session", 1).await?;
+        conn.dispatch("MESSAGE_CREATE", message(10, 20, 30, "hello"))
+            .await?;
+
+        assert_eq!(conn.expect_command(1).await?, json!(2));
+        conn.heartbeat_ack().await?;
+        assert_eq!(conn.expect_command(1).await?, json!(2));
+        Ok(())
+    })
+}
+
+#[test]
+fn answers_heartbeat_requests() -> Result<()> {
+    let (bot, gateway, _rest) = setup()?;
+    let (sender, _events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.auto_ack = false;
+        conn.handshake("session", 1).await?;
+        conn.send_event(1, None, json!(null)).await?;
+        assert_eq!(conn.expect_command(1).await?, json!(1));
+        Ok(())
+    })
+}
+
+#[test]
+fn handles_channels_side_by_side() -> Result<()> {
+    let (bot, gateway, _rest) = setup()?;
+    let (sender, mut events) = mpsc::unbounded();
+    run_scripted(&bot, SlowRecorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.auto_ack = false;
+        conn.handshake("session", 1).await?;
+        conn.dispatch("MESSAGE_CREATE", message(10, 20, 30, "slow"))
+            .await?;
+        // dispatches the bot does not parse still keep to their channel
+        conn.dispatch("MESSAGE_DELETE", json!({ "id": "9", "channel_id": "21" }))
+            .await?;
+        conn.dispatch("MESSAGE_CREATE", message(11, 21, 30, "quick"))
+            .await?;
+        conn.dispatch("MESSAGE_CREATE", message(12, 20, 30, "after"))
+            .await?;
+        assert_eq!(events.next().await.unwrap(), "21 quick");
+
+        // the shard is not held up by the slow handler
+        conn.send_event(1, None, json!(null)).await?;
+        assert_eq!(conn.expect_command(1).await?, json!(5));
+
+        assert_eq!(events.next().await.unwrap(), "20 slow");
+        assert_eq!(events.next().await.unwrap(), "20 after");
+        Ok(())
+    })
+}
+
+#[test]
+fn handles_sequentially_when_asked() -> Result<()> {
+    let (bot, gateway, _rest) = setup_with(|config| {
+        config.ordering = DispatchOrdering::Sequential;
+    })?;
+    let (sender, mut events) = mpsc::unbounded();
+    run_scripted(&bot, SlowRecorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.handshake("session", 1).await?;
+        conn.dispatch("MESSAGE_CREATE", message(10, 20, 30, "slow"))
+            .await?;
+        conn.dispatch("MESSAGE_CREATE", message(11, 21, 30, "quick"))
+            .await?;
+        assert_eq!(events.next().await.unwrap(), "20 slow");
+        assert_eq!(events.next().await.unwrap(), "21 quick");
+        Ok(())
+    })
+}
+
+#[test]
+fn shuts_down_once_dispatches_are_handled() -> Result<()> {
+    let (bot, gateway, _rest) = setup()?;
+    let handle = bot.handle();
+    let (sender, mut events) = mpsc::unbounded();
+    let result = run_until_stopped(&bot, SlowRecorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.handshake("session", 1).await?;
+        conn.dispatch("MESSAGE_CREATE", message(10, 20, 30, "slow"))
+            .await?;
+        conn.dispatch("MESSAGE_CREATE", message(11, 21, 30, "quick"))
+            .await?;
+        assert_eq!(events.next().await.unwrap(), "21 quick");
+
+        handle.shut_down();
+        let frame = conn.expect_closed().await?.expect("no close frame");
+        assert_eq!(u16::from(frame.code), 1000);
+        Ok(())
+    })?;
+    result?;
+    assert_eq!(events.try_next()?.unwrap(), "20 slow");
+    Ok(())
+}
+
+#[test]
+fn shuts_down_while_waiting_to_start() -> Result<()> {
+    let (bot, _gateway, _rest) = setup_with(|config| {
+        // nothing listens here, so looking up the gateway keeps failing
+        config.api_root = "http://127.0.0.1:1/api".to_string();
+        config.reconnect.initial_delay_ms = 60_000;
+        config.reconnect.max_delay_ms = 60_000;
+    })?;
+    let (sender, _events) = mpsc::unbounded();
+    let started = Instant::now();
+    bot.run_until(
+        Recorder(sender),
+        Timer::after(Duration::from_millis(200)).map(|_| ()),
+    )?;
+    assert!(started.elapsed() < Duration::from_secs(5));
+    Ok(())
+}
+
+/// A file of its own for `test` to write to, with nothing in it yet.
+fn temp_file(test: &str) -> String {
+    let path = std::env::temp_dir().join(format!("taco_bot-{}.json", test));
+    let _ = std::fs::remove_file(&path);
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn saves_session_on_shutdown() -> Result<()> {
+    let path = temp_file("saves_session_on_shutdown");
+    let (bot, gateway, _rest) = setup_with(|config| {
+        config.session_file = Some(path.clone());
+    })?;
+    let handle = bot.handle();
+    let (sender, mut events) = mpsc::unbounded();
+    let result = run_until_stopped(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.handshake("session", 1).await?;
+        conn.dispatch("MESSAGE_CREATE", message(10, 20, 30, "hello"))
+            .await?;
+        events.next().await;
+        events.next().await;
+
+        handle.shut_down();
+        let frame = conn.expect_closed().await?.expect("no close frame");
+        // closing normally would end the session
+        assert_ne!(u16::from(frame.code), 1000);
+        Ok(())
+    })?;
+    result?;
+
+    let saved: Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+    assert_eq!(saved["shard_count"], 1);
+    assert_eq!(saved["sessions"]["0"]["session_id"], "session");
+    assert_eq!(saved["sessions"]["0"]["seq"], 2);
+    assert_eq!(saved["sessions"]["0"]["resume_url"], gateway.url);
+    Ok(())
+}
+
+#[test]
+fn resumes_saved_session() -> Result<()> {
+    let path = temp_file("resumes_saved_session");
+    let (bot, gateway, _rest) = setup_with(|config| {
+        config.session_file = Some(path.clone());
+    })?;
+    std::fs::write(
+        &path,
+        json!({
+            "shard_count": 1,
+            "sessions": {
+                "0": { "session_id": "saved", "seq": 5, "resume_url": gateway.url },
+            },
+        })
+        .to_string(),
+    )?;
+    let (sender, mut events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        let resume = conn.expect_command(6).await?;
+        assert_eq!(resume["session_id"], "saved");
+        assert_eq!(resume["seq"], 5);
+        conn.hello(45000).await?;
+        conn.dispatch("MESSAGE_CREATE", message(10, 20, 30, "missed"))
+            .await?;
+        conn.dispatch("RESUMED", json!(null)).await?;
+        assert_eq!(events.next().await.unwrap(), "MESSAGE_CREATE missed");
+        assert_eq!(events.next().await.unwrap(), "RESUMED");
+        Ok(())
+    })
+}
+
+#[test]
+fn identifies_when_saved_session_is_invalid() -> Result<()> {
+    let path = temp_file("identifies_when_saved_session_is_invalid");
+    let (bot, gateway, _rest) = setup_with(|config| {
+        config.session_file = Some(path.clone());
+    })?;
+    std::fs::write(
+        &path,
+        json!({
+            "shard_count": 1,
+            "sessions": {
+                "0": { "session_id": "stale", "seq": 5, "resume_url": gateway.url },
+            },
+        })
+        .to_string(),
+    )?;
+    let (sender, mut events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.expect_command(6).await?;
+        conn.hello(45000).await?;
+        conn.invalid_session(false).await?;
+        conn.expect_command(2).await?;
+        conn.ready("fresh", 1).await?;
+        assert_eq!(events.next().await.unwrap(), "READY fresh");
+        Ok(())
+    })
+}
+
+#[test]
+fn records_gateway_traffic() -> Result<()> {
+    let path = temp_file("records_gateway_traffic");
+    let (bot, gateway, _rest) = setup_with(|config| {
+        config.record_file = Some(path.clone());
+    })?;
+    let (sender, mut events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.handshake("session", 1).await?;
+        conn.dispatch("MESSAGE_CREATE", message(10, 20, 30, "hello"))
+            .await?;
+        events.next().await;
+        events.next().await;
+        Ok(())
+    })?;
+
+    let frames = std::fs::read_to_string(&path)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<Value>, _>>()?;
+    let summary: Vec<_> = frames
+        .iter()
+        .map(|frame| (frame["direction"].clone(), frame["payload"]["op"].clone()))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            (json!("out"), json!(2)),
+            (json!("in"), json!(10)),
+            (json!("in"), json!(0)),
+            (json!("in"), json!(0)),
+        ]
+    );
+    assert_eq!(frames[0]["payload"]["d"]["token"], "<redacted>");
+    assert_eq!(frames[0]["shard"], json!([0, 1]));
+    assert_eq!(frames[3]["payload"]["d"]["content"], "hello");
+    Ok(())
+}
+
+#[test]
+fn replays_recorded_dispatches() -> Result<()> {
+    let path = temp_file("replays_recorded_dispatches");
+    let frame = |direction: &str, payload: Value| {
+        json!({
+            "time": "2020-10-01T12:00:00Z",
+            "shard": [0, 1],
+            "direction": direction,
+            "payload": payload,
+        })
+        .to_string()
+    };
+    let recording = [
+        frame("out", json!({ "op": 2, "d": { "token": "<redacted>" } })),
+        frame(
+            "in",
+            json!({ "op": 10, "d": { "heartbeat_interval": 45000 } }),
+        ),
+        frame(
+            "in",
+            json!({ "op": 0, "s": 1, "t": "MESSAGE_CREATE", "d": message(10, 20, 30, "hello") }),
+        ),
+    ];
+    std::fs::write(&path, recording.join("\n"))?;
+
+    let (sender, mut events) = mpsc::unbounded();
+    record::replay(&path, Recorder(sender))?;
+    assert_eq!(events.try_next()?.unwrap(), "MESSAGE_CREATE hello");
+    assert_eq!(events.try_next()?, None);
+    Ok(())
+}
+
+/// Reports every message it receives, prefixed by its name.
+struct Named(&'static str, mpsc::UnboundedSender<String>);
+
+impl AsyncDispatchHandler for Named {
+    fn handle_message<'a>(
+        &'a self,
+        payload: DispatchPayload<'a>,
+        _shard: ShardInfo,
+        _client: &'a Client,
+    ) -> AsyncDispatchFuture<'a> {
+        if let DispatchPayload::MessageCreate(message) = payload {
+            let _ = self
+                .1
+                .unbounded_send(format!("{} {}", self.0, message.content.as_str()));
+        }
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+#[test]
+fn event_bus_runs_handlers_by_priority_after_middleware() -> Result<()> {
+    let (sender, events) = mpsc::unbounded();
+    let bus = bus::EventBus::new()
+        .with_middleware(bus::IgnoreOwnMessages::default())
+        .with_middleware(bus::IgnoreChannels(vec![Id::from_str("21")?]))
+        .with_middleware(|payload: &DispatchPayload, _| match payload {
+            DispatchPayload::MessageCreate(m) if m.content.as_str() == "secret" => bus::Flow::Stop,
+            _ => bus::Flow::Continue,
+        })
+        .with_handler(0, Named("last", sender.clone()))
+        .with_handler(5, Named("first", sender.clone()))
+        .with_handler(0, Named("also last", sender));
+
+    let client = client::Client::stub();
+    let shard = ShardInfo { id: 0, count: 1 };
+    let ready = json!({ "op": 0, "s": 1, "t": "READY", "d": {
+        "session_id": "session",
+        "user": user(1, "bot"),
+    }});
+    let dispatches = [
+        ready,
+        json!({ "op": 0, "s": 2, "t": "MESSAGE_CREATE", "d": message(10, 20, 1, "own") }),
+        json!({ "op": 0, "s": 3, "t": "MESSAGE_CREATE", "d": message(11, 21, 30, "ignored") }),
+        json!({ "op": 0, "s": 4, "t": "MESSAGE_CREATE", "d": message(12, 20, 30, "secret") }),
+        json!({ "op": 0, "s": 5, "t": "MESSAGE_CREATE", "d": message(13, 20, 30, "hello") }),
+    ];
+    async_io::block_on(async {
+        for dispatch in dispatches.iter() {
+            let text = dispatch.to_string();
+            if let Event::Dispatch(d) = serde_json::from_str(&text)? {
+                bus.handle_message(d.payload, shard, &client).await?;
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    drop(bus);
+    let received: Vec<String> = async_io::block_on(events.collect());
+    assert_eq!(received, ["first hello", "last hello", "also last hello"]);
+    Ok(())
+}
+
+#[test]
+fn collects_matching_dispatches_until_time_is_up() -> Result<()> {
+    let (bot, gateway, _rest) = setup()?;
+    let handle = bot.handle();
+    let (sender, _events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.handshake("session", 1).await?;
+        let collector = handle.collect(
+            |payload| match payload {
+                DispatchPayload::MessageCreate(m) => m.content.as_str().starts_with('!'),
+                _ => false,
+            },
+            Duration::from_millis(300),
+        );
+        conn.dispatch("MESSAGE_CREATE", message(10, 20, 30, "!one"))
+            .await?;
+        conn.dispatch("MESSAGE_CREATE", message(11, 20, 30, "other"))
+            .await?;
+        conn.dispatch("MESSAGE_CREATE", message(12, 20, 30, "!two"))
+            .await?;
+        let collected: Vec<String> = collector
+            .map(|dispatch| describe(&dispatch.payload()))
+            .collect()
+            .await;
+        assert_eq!(collected, ["MESSAGE_CREATE !one", "MESSAGE_CREATE !two"]);
+
+        let answer = handle.wait_for(
+            |payload| matches!(payload, DispatchPayload::MessageCreate(_)),
+            Duration::from_millis(300),
+        );
+        conn.dispatch("MESSAGE_CREATE", message(13, 20, 30, "yes"))
+            .await?;
+        let answer = answer.await.expect("the message should be waited for");
+        assert_eq!(describe(&answer.payload()), "MESSAGE_CREATE yes");
+
+        let answer = handle.wait_for(|_| true, Duration::from_millis(100));
+        assert!(answer.await.is_none());
+        Ok(())
+    })
+}
+
+#[test]
+fn measures_heartbeat_latency() -> Result<()> {
+    let (bot, gateway, _rest) = setup()?;
+    let handle = bot.handle();
+    let (sender, mut events) = mpsc::unbounded();
+    assert_eq!(handle.latency(0), None);
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.auto_ack = false;
+        conn.handshake("session", 1).await?;
+        conn.send_event(1, None, json!(null)).await?;
+        conn.expect_command(1).await?;
+        Timer::after(Duration::from_millis(50)).await;
+        conn.heartbeat_ack().await?;
+        conn.dispatch("MESSAGE_CREATE", message(10, 20, 30, "hello"))
+            .await?;
+        events.next().await;
+        events.next().await;
+
+        let latency = handle.latency(0).expect("latency should be measured");
+        assert!(latency >= Duration::from_millis(50));
+        assert_eq!(handle.average_latency(0), Some(latency));
+        Ok(())
+    })
+}
+
+#[test]
+fn resumes_after_reconnect_request() -> Result<()> {
+    let (bot, gateway, _rest) = setup()?;
+    let (sender, _events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.handshake("session", 1).await?;
+        conn.dispatch("MESSAGE_CREATE", message(10, 20, 30, "hello"))
+            .await?;
+        conn.reconnect().await?;
+        conn.expect_closed().await?;
+
+        let mut conn = gateway.accept().await?;
+        let resume = conn.expect_command(6).await?;
+        assert_eq!(resume["token"], TOKEN);
+        assert_eq!(resume["session_id"], "session");
+        assert_eq!(resume["seq"], 2);
+        Ok(())
+    })
+}
+
+#[test]
+fn resumed_session_keeps_sequence() -> Result<()> {
+    let (bot, gateway, _rest) = setup()?;
+    let (sender, mut events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.handshake("session", 1).await?;
+        conn.reconnect().await?;
+        conn.expect_closed().await?;
+
+        let mut conn = gateway.accept().await?;
+        assert_eq!(conn.expect_command(6).await?["seq"], 1);
+        conn.seq = 1;
+        conn.hello(45000).await?;
+        conn.dispatch("RESUMED", json!({ "_trace": [] })).await?;
+        conn.dispatch("MESSAGE_CREATE", message(10, 20, 30, "hello"))
+            .await?;
+        assert_eq!(events.next().await.unwrap(), "READY session");
+        assert_eq!(events.next().await.unwrap(), "RESUMED");
+        assert_eq!(events.next().await.unwrap(), "MESSAGE_CREATE hello");
+        conn.reconnect().await?;
+        conn.expect_closed().await?;
+
+        let mut conn = gateway.accept().await?;
+        let resume = conn.expect_command(6).await?;
+        assert_eq!(resume["session_id"], "session");
+        assert_eq!(resume["seq"], 3);
+        Ok(())
+    })
+}
+
+#[test]
+fn identifies_again_when_resume_fails() -> Result<()> {
+    let (bot, gateway, _rest) = setup()?;
+    let (sender, mut events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.handshake("old", 1).await?;
+        conn.reconnect().await?;
+        conn.expect_closed().await?;
+
+        let mut conn = gateway.accept().await?;
+        conn.auto_ack = false;
+        conn.expect_command(6).await?;
+        conn.invalid_session(false).await?;
+        // the shard keeps heartbeating while it waits to identify
+        conn.send_event(1, None, json!(null)).await?;
+        conn.expect_command(1).await?;
+        conn.handshake("new", 1).await?;
+        assert_eq!(events.next().await.unwrap(), "READY old");
+        assert_eq!(events.next().await.unwrap(), "READY new");
+        conn.reconnect().await?;
+        conn.expect_closed().await?;
+
+        let mut conn = gateway.accept().await?;
+        let resume = conn.expect_command(6).await?;
+        assert_eq!(resume["session_id"], "new");
+        assert_eq!(resume["seq"], 1);
+        Ok(())
+    })
+}
+
+#[test]
+fn authentication_failure_is_fatal() -> Result<()> {
+    let (bot, gateway, _rest) = setup()?;
+    let (sender, _events) = mpsc::unbounded();
+    let result = run_until_stopped(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.expect_command(2).await?;
+        conn.close(4004, "Authentication failed.").await
+    })?;
+
+    let error = result.expect_err("bot should stop after authentication fails");
+    let closed = error
+        .downcast_ref::<GatewayClosed>()
+        .expect("error should be a close code");
+    assert_eq!(closed.code, GatewayCloseCode::AuthenticationFailed);
+    assert!(closed.code.is_fatal());
+    Ok(())
+}
+
+#[test]
+fn session_timeout_identifies_again() -> Result<()> {
+    let (bot, gateway, _rest) = setup()?;
+    let (sender, mut events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.handshake("old", 1).await?;
+        conn.close(4009, "Session timed out.").await?;
+
+        let mut conn = gateway.accept().await?;
+        conn.handshake("new", 1).await?;
+        assert_eq!(events.next().await.unwrap(), "READY old");
+        assert_eq!(events.next().await.unwrap(), "READY new");
+        Ok(())
+    })
+}
+
+#[test]
+fn updates_presence() -> Result<()> {
+    let (bot, gateway, _rest) = setup()?;
+    let handle = bot.handle();
+    let (sender, mut events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        let identify = conn.handshake("old", 1).await?;
+        assert_eq!(identify.get("presence"), None);
+        events.next().await;
+
+        handle.set_presence(Status::Idle, Some(Activity::listening("the wind")));
+        assert_eq!(
+            conn.expect_command(3).await?,
+            json!({
+                "since": null,
+                "status": "idle",
+                "afk": false,
+                "activities": [{ "name": "the wind", "type": 2 }],
+            })
+        );
+        handle.set_activity(Some(Activity::custom("learning")));
+        assert_eq!(
+            conn.expect_command(3).await?,
+            json!({
+                "since": null,
+                "status": "idle",
+                "afk": false,
+                "activities": [{ "name": "Custom Status", "type": 4, "state": "learning" }],
+            })
+        );
+
+        // a new session starts out with the presence last set
+        conn.close(4009, "Session timed out.").await?;
+        let mut conn = gateway.accept().await?;
+        let identify = conn.handshake("new", 1).await?;
+        assert_eq!(identify["presence"]["status"], "idle");
+        assert_eq!(identify["presence"]["activities"][0]["state"], "learning");
+        Ok(())
+    })
+}
+
+#[test]
+fn assembles_member_chunks() -> Result<()> {
+    let (bot, gateway, _rest) = setup()?;
+    let handle = bot.handle();
+    let (sender, mut events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.handshake("session", 1).await?;
+        events.next().await;
+
+        let guild = Id::from_str("41771983423143937")?;
+        let request = RequestGuildMembers::query(guild, "fr", 10);
+        let server = async {
+            let request = conn.expect_command(8).await?;
+            assert_eq!(request["guild_id"], "41771983423143937");
+            assert_eq!(request["query"], "fr");
+            assert_eq!(request["limit"], 10);
+            assert_eq!(request.get("user_ids"), None);
+            let chunk = |index: u32, nonce: &Value, members: Vec<Value>| {
+                json!({
+                    "guild_id": "41771983423143937",
+                    "members": members,
+                    "chunk_index": index,
+                    "chunk_count": 2,
+                    "not_found": [],
+                    "nonce": nonce,
+                })
+            };
+            let member = |id, name| json!({ "user": user(id, name), "nick": null });
+            let nonce = &request["nonce"];
+            conn.dispatch(
+                "GUILD_MEMBERS_CHUNK",
+                chunk(0, nonce, vec![member(2, "fred")]),
+            )
+            .await?;
+            // chunks of other requests are left alone
+            conn.dispatch(
+                "GUILD_MEMBERS_CHUNK",
+                chunk(1, &json!("other"), vec![member(5, "frank")]),
+            )
+            .await?;
+            conn.dispatch(
+                "GUILD_MEMBERS_CHUNK",
+                chunk(1, nonce, vec![member(3, "frida")]),
+            )
+            .await?;
+            Ok::<_, anyhow::Error>(())
+        };
+        let (members, server) = future::join(handle.request_guild_members(request), server).await;
+        server?;
+        let names: Vec<_> = members?
+            .members
+            .into_iter()
+            .map(|member| member.tag())
+            .collect();
+        assert_eq!(names, vec!["fred#0001", "frida#0001"]);
+
+        // handlers still see every chunk
+        assert_eq!(events.next().await.unwrap(), "GUILD_MEMBERS_CHUNK 1/2");
+        assert_eq!(events.next().await.unwrap(), "GUILD_MEMBERS_CHUNK 2/2");
+        assert_eq!(events.next().await.unwrap(), "GUILD_MEMBERS_CHUNK 2/2");
+        Ok(())
+    })
+}
+
+#[test]
+fn rejects_member_requests_with_a_nonce_in_use() -> Result<()> {
+    let (bot, gateway, _rest) = setup()?;
+    let handle = bot.handle();
+    let (sender, _events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.handshake("session", 1).await?;
+
+        let guild = Id::from_str("41771983423143937")?;
+        let mut request = RequestGuildMembers::query(guild, "fr", 10);
+        request.nonce = Some(String::from("same"));
+        let mut first = Box::pin(handle.request_guild_members(request.clone()));
+        assert!(futures::poll!(&mut first).is_pending());
+        assert!(handle.request_guild_members(request).await.is_err());
+
+        conn.expect_command(8).await?;
+        let chunk = json!({
+            "guild_id": "41771983423143937",
+            "members": [],
+            "chunk_index": 0,
+            "chunk_count": 1,
+            "nonce": "same",
+        });
+        conn.dispatch("GUILD_MEMBERS_CHUNK", chunk).await?;
+        first.await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn fails_member_requests_of_ended_sessions() -> Result<()> {
+    let (bot, gateway, _rest) = setup()?;
+    let handle = bot.handle();
+    let (sender, _events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.handshake("old", 1).await?;
+
+        let guild = Id::from_str("41771983423143937")?;
+        let request = RequestGuildMembers::query(guild, "fr", 10);
+        let server = async {
+            conn.expect_command(8).await?;
+            conn.close(4009, "Session timed out.").await?;
+            gateway.accept().await?.handshake("new", 1).await?;
+            Ok::<_, anyhow::Error>(())
+        };
+        let (members, server) = future::join(handle.request_guild_members(request), server).await;
+        server?;
+        assert!(members.is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn failed_session_is_retried() -> Result<()> {
+    let (bot, gateway, _rest) = setup()?;
+    let (sender, mut events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.expect_command(2).await?;
+        drop(conn);
+
+        let mut conn = gateway.accept().await?;
+        conn.handshake("session", 1).await?;
+        assert_eq!(events.next().await.unwrap(), "READY session");
+        Ok(())
+    })
+}
+
+#[test]
+fn backoff_grows_until_capped_and_resets() {
+    let policy = ReconnectPolicy {
+        initial_delay_ms: 100,
+        max_delay_ms: 1000,
+        multiplier: 3.0,
+        jitter: 0.0,
+        stable_after_ms: 0,
+        max_attempts: Some(5),
+    };
+    let mut rng = rand::thread_rng();
+    let mut backoff = Backoff::new(&policy);
+    let mut delays = || backoff.next_delay(&mut rng).map(|d| d.as_millis());
+    assert_eq!(delays(), Some(100));
+    assert_eq!(delays(), Some(300));
+    assert_eq!(delays(), Some(900));
+    assert_eq!(delays(), Some(1000));
+    assert_eq!(delays(), Some(1000));
+    assert_eq!(delays(), None);
+
+    backoff.reset();
+    assert_eq!(
+        backoff.next_delay(&mut rng),
+        Some(Duration::from_millis(100))
+    );
+}
+
+#[test]
+fn backoff_jitter_stays_in_range() {
+    let policy = ReconnectPolicy {
+        initial_delay_ms: 1000,
+        jitter: 0.5,
+        ..ReconnectPolicy::default()
+    };
+    let mut rng = rand::thread_rng();
+    for _ in 0..100 {
+        let delay = Backoff::new(&policy).next_delay(&mut rng).unwrap();
+        assert!(delay >= Duration::from_millis(500) && delay <= Duration::from_millis(1000));
+    }
+}
+
+#[test]
+fn command_limiter_keeps_room_for_heartbeats() {
+    let start = Instant::now();
+    let mut limiter = ratelimit::CommandLimiter::new(start);
+    for _ in 0..115 {
+        assert_eq!(limiter.reserve(false, start), None);
+    }
+    let half_a_second = Some(Duration::from_millis(500));
+    assert_eq!(limiter.command_delay(start), half_a_second);
+    assert_eq!(limiter.reserve(false, start), half_a_second);
+    for _ in 0..4 {
+        assert_eq!(limiter.reserve(true, start), None);
+    }
+    assert_eq!(limiter.reserve(true, start), half_a_second);
+
+    assert_eq!(limiter.command_delay(start + Duration::from_secs(60)), None);
+}
+
+#[test]
+fn drops_commands_too_large_for_the_gateway() -> Result<()> {
+    let (bot, gateway, _rest) = setup()?;
+    let handle = bot.handle();
+    let (sender, _events) = mpsc::unbounded();
+    run_scripted(&bot, Recorder(sender), async {
+        let mut conn = gateway.accept().await?;
+        conn.handshake("session", 1).await?;
+
+        handle.set_activity(Some(Activity::playing("a".repeat(5000))));
+        handle.set_activity(Some(Activity::playing("a game")));
+        let presence = conn.expect_command(3).await?;
+        assert_eq!(presence["activities"][0]["name"], "a game");
+        Ok(())
+    })
+}
+
+#[test]
+fn client_sends_messages_and_reactions() -> Result<()> {
+    let gateway = MockGateway::bind()?;
+    let mut rest = MockRest::start(&gateway.url)?;
+    let client = Client::new(&TokenBuf::from(TOKEN), &rest.root);
+    let channel: Id = "20".parse()?;
+    let message: Id = "10".parse()?;
+    async_io::block_on(async {
+        client.create_message(channel, "hi there").await?;
+        let request = rest.next_request().await?;
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/channels/20/messages");
+        assert_eq!(request.headers["authorization"], format!("Bot {}", TOKEN));
+        assert_eq!(request.json(), json!({ "content": "hi there" }));
+
+        client.create_reaction(channel, message, "💦").await?;
+        let request = rest.next_request().await?;
+        assert_eq!(request.method, "PUT");
+        assert_eq!(
+            request.path,
+            "/channels/20/messages/10/reactions/%F0%9F%92%A6/@me"
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn sends_rich_messages_and_returns_them() -> Result<()> {
+    let gateway = MockGateway::bind()?;
+    let mut rest = MockRest::with_responder(&gateway.url, |_| {
+        Response::json(200, message(11, 20, 1, "look at this"))
+    })?;
+    let client = Client::new(&TokenBuf::from(TOKEN), &rest.root);
+    let original = message(10, 20, 30, "eg!show").to_string();
+    let original: Message = serde_json::from_str(&original)?;
+    let embed = Embed::new()
+        .with_title("A title")
+        .with_description("Some words")
+        .with_field("Left", "1", true)
+        .with_field("Right", "2", true)
+        .with_color(0xff0000)
+        .with_footer("Small print")
+        .with_thumbnail("https://example.com/image.png");
+    let create = CreateMessage::new()
+        .with_content("look at this")
+        .with_embed(embed)
+        .reply_to(&original)
+        .with_allowed_mentions(AllowedMentions::none())
+        .with_tts(true)
+        .with_nonce("abc");
+    async_io::block_on(async {
+        let mut response = client.send_message(original.channel_id, &create).await?;
+        let created = response.get_response().await?;
+        assert_eq!(created.id, "11".parse()?);
+        assert_eq!(created.content.as_str(), "look at this");
+
+        let request = rest.next_request().await?;
+        assert_eq!(request.path, "/channels/20/messages");
+        assert_eq!(
+            request.json(),
+            json!({
+                "content": "look at this",
+                "embed": {
+                    "title": "A title",
+                    "description": "Some words",
+                    "fields": [
+                        { "name": "Left", "value": "1", "inline": true },
+                        { "name": "Right", "value": "2", "inline": true },
+                    ],
+                    "color": 0xff0000,
+                    "footer": { "text": "Small print" },
+                    "thumbnail": { "url": "https://example.com/image.png" },
+                },
+                "message_reference": { "message_id": "10", "channel_id": "20" },
+                "allowed_mentions": { "parse": [], "users": [], "roles": [], "replied_user": false },
+                "tts": true,
+                "nonce": "abc",
+            })
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn uploads_attachments_as_form_data() -> Result<()> {
+    let gateway = MockGateway::bind()?;
+    let mut rest = MockRest::start(&gateway.url)?;
+    let client = Client::new(&TokenBuf::from(TOKEN), &rest.root);
+    let path = temp_file("uploads_attachments_as_form_data");
+    std::fs::write(&path, "from disk")?;
+    let create = CreateMessage::new()
+        .with_content("files!")
+        .with_attachment(Attachment::from_bytes("memory.txt", "from memory"))
+        .with_attachment(Attachment::from_path(&path)?);
+    let too_many = (0..=MAX_ATTACHMENTS).fold(CreateMessage::new(), |create, i| {
+        create.with_attachment(Attachment::from_bytes(format!("{}.txt", i), "x"))
+    });
+    async_io::block_on(async {
+        // nothing is sent for messages Discord would reject
+        assert!(client
+            .send_message("20".parse()?, &CreateMessage::new())
+            .await
+            .is_err());
+        assert!(client.send_message("20".parse()?, &too_many).await.is_err());
+        client.send_message("20".parse()?, &create).await?;
+        let request = rest.next_request().await?;
+        let boundary = request.headers["content-type"]
+            .strip_prefix("multipart/form-data; boundary=")
+            .unwrap()
+            .to_string();
+        let parts: Vec<_> = request
+            .body_str()
+            .split(&format!("--{}", boundary))
+            .collect();
+        assert_eq!(parts.len(), 5);
+        assert_eq!(parts[0], "");
+        assert_eq!(
+            parts[1],
+            "\r\nContent-Disposition: form-data; name=\"payload_json\"\r\n\
+             Content-Type: application/json\r\n\r\n{\"content\":\"files!\"}\r\n"
+        );
+        assert!(parts[2].contains("name=\"file0\"; filename=\"memory.txt\""));
+        assert!(parts[2].ends_with("\r\n\r\nfrom memory\r\n"));
+        assert!(parts[3].contains(
+            "name=\"file1\"; filename=\"taco_bot-uploads_attachments_as_form_data.json\""
+        ));
+        assert!(parts[3].ends_with("\r\n\r\nfrom disk\r\n"));
+        assert_eq!(parts[4], "--\r\n");
+        Ok(())
+    })
+}
+
+#[test]
+fn edits_and_deletes_messages() -> Result<()> {
+    let gateway = MockGateway::bind()?;
+    let mut rest = MockRest::with_responder(&gateway.url, |_| {
+        Response::json(200, message(10, 20, 1, "changed"))
+    })?;
+    let client = Client::new(&TokenBuf::from(TOKEN), &rest.root);
+    let channel: Id = "20".parse()?;
+    // snowflakes made a day and three weeks ago
+    let day = 24 * 60 * 60 * 1000;
+    let made_ago = |ms: i64| {
+        Id::from_str(
+            &(((Utc::now().timestamp_millis() - ms - 1_420_070_400_000) as u64) << 22).to_string(),
+        )
+    };
+    let (recent, old) = (made_ago(day)?, made_ago(21 * day)?);
+    assert!(recent.timestamp() <= Utc::now().timestamp_millis() - day);
+    async_io::block_on(async {
+        let edit = EditMessage::new().with_content("changed");
+        let mut response = client.edit_message(channel, "10".parse()?, &edit).await?;
+        assert_eq!(response.get_response().await?.content.as_str(), "changed");
+        let request = rest.next_request().await?;
+        assert_eq!(request.method, "PATCH");
+        assert_eq!(request.path, "/channels/20/messages/10");
+        assert_eq!(request.json(), json!({ "content": "changed" }));
+
+        client.delete_message(channel, "10".parse()?).await?;
+        let request = rest.next_request().await?;
+        assert_eq!(request.method, "DELETE");
+        assert_eq!(request.path, "/channels/20/messages/10");
+
+        // nothing is sent for deletes Discord would reject
+        assert!(client
+            .bulk_delete_messages(channel, &[recent])
+            .await
+            .is_err());
+        assert!(client
+            .bulk_delete_messages(channel, &vec![recent; 101])
+            .await
+            .is_err());
+        assert!(client
+            .bulk_delete_messages(channel, &[recent, old])
+            .await
+            .is_err());
+        client
+            .bulk_delete_messages(channel, &[recent, recent])
+            .await?;
+        let request = rest.next_request().await?;
+        assert_eq!(request.path, "/channels/20/messages/bulk-delete");
+        assert_eq!(
+            request.json(),
+            json!({ "messages": [recent.to_string(), recent.to_string()] })
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn reports_discord_api_errors() -> Result<()> {
+    let gateway = MockGateway::bind()?;
+    let rest = MockRest::with_responder(&gateway.url, |request| match request.method.as_str() {
+        "PUT" => Response::json(
+            403,
+            json!({ "code": 50013, "message": "Missing Permissions" }),
+        ),
+        "GET" => Response::json(404, json!({ "code": 10003, "message": "Unknown Channel" })),
+        _ => Response::json(
+            400,
+            json!({
+                "code": 50035,
+                "message": "Invalid Form Body",
+                "errors": { "content": { "_errors": [{
+                    "code": "BASE_TYPE_MAX_LENGTH",
+                    "message": "Must be 2000 or fewer in length.",
+                }] } },
+            }),
+        ),
+    })?;
+    let client = Client::new(&TokenBuf::from(TOKEN), &rest.root);
+    let channel: Id = "20".parse()?;
+    async_io::block_on(async {
+        let e = client
+            .create_reaction(channel, "10".parse()?, "💦")
+            .await
+            .unwrap_err();
+        let e = e.downcast_ref::<DiscordApiError>().unwrap();
+        assert_eq!(e.status, 403);
+        assert_eq!(e.code, ApiErrorCode::MissingPermissions);
+        assert_eq!(e.message, "Missing Permissions");
+
+        let e = match client.get_channel_messages(channel, None).await {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e,
+        };
+        assert_eq!(
+            e.downcast_ref::<DiscordApiError>().unwrap().code,
+            ApiErrorCode::UnknownChannel
+        );
+
+        let e = client.create_message(channel, "hi").await.unwrap_err();
+        let e = e.downcast_ref::<DiscordApiError>().unwrap();
+        assert_eq!(e.code, ApiErrorCode::InvalidFormBody);
+        assert_eq!(
+            e.errors,
+            vec![api_error::FieldError {
+                path: "content".to_string(),
+                code: "BASE_TYPE_MAX_LENGTH".to_string(),
+                message: "Must be 2000 or fewer in length.".to_string(),
+            }]
+        );
+        assert_eq!(
+            e.to_string(),
+            "Discord API error 400 (50035 invalid form body): Invalid Form Body; \
+             content: Must be 2000 or fewer in length. (BASE_TYPE_MAX_LENGTH)"
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn retries_rate_limited_requests() -> Result<()> {
+    let gateway = MockGateway::bind()?;
+    let limited = AtomicBool::new(false);
+    let mut rest = MockRest::with_responder(&gateway.url, move |_| {
+        if limited.swap(true, AtomicOrdering::SeqCst) {
+            Response::json(200, json!({}))
+        } else {
+            let body = json!({ "message": "You are being rate limited.", "retry_after": 0.3, "global": false });
+            Response::json(429, body).with_header("Retry-After", "1")
+        }
+    })?;
+    let client = Client::new(&TokenBuf::from(TOKEN), &rest.root);
+    async_io::block_on(async {
+        let start = Instant::now();
+        client.create_message("20".parse()?, "hi there").await?;
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        for _ in 0..2 {
+            assert_eq!(rest.next_request().await?.path, "/channels/20/messages");
+        }
+        Ok(())
+    })
+}
+
+#[test]
+fn waits_for_exhausted_buckets() -> Result<()> {
+    let gateway = MockGateway::bind()?;
+    let rest = MockRest::with_responder(&gateway.url, |_| {
+        Response::json(200, json!({}))
+            .with_header("X-RateLimit-Bucket", "messages")
+            .with_header("X-RateLimit-Remaining", "0")
+            .with_header("X-RateLimit-Reset-After", "0.3")
+    })?;
+    let client = Client::new(&TokenBuf::from(TOKEN), &rest.root);
+    async_io::block_on(async {
+        let start = Instant::now();
+        future::try_join(
+            client.create_message("20".parse()?, "first"),
+            client.create_message("20".parse()?, "second"),
+        )
+        .await?;
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        // other channels have buckets of their own
+        let start = Instant::now();
+        client.create_message("21".parse()?, "elsewhere").await?;
+        assert!(start.elapsed() < Duration::from_millis(300));
+        Ok(())
+    })
+}
+
+#[test]
+fn rest_limiter_keys_routes_by_major_ids() {
+    let reaction = ratelimit::Route::new("PUT", "/channels/20/messages/10/reactions/%F0/@me");
+    assert_eq!(
+        reaction.to_string(),
+        "PUT /channels/:major/messages/:id/reactions/:emoji/@me (20)"
+    );
+    let messages = ratelimit::Route::new("GET", "/channels/20/messages?before=10");
+    let other_messages = ratelimit::Route::new("GET", "/channels/21/messages");
+
+    let now = Instant::now();
+    let limiter = ratelimit::RestLimiter::default();
+    limiter.limited(&messages, Duration::from_secs(2), false, now);
+    assert_eq!(limiter.delay(&messages, now), Some(Duration::from_secs(2)));
+    assert_eq!(limiter.delay(&other_messages, now), None);
+
+    limiter.limited(&reaction, Duration::from_secs(5), true, now);
+    assert_eq!(
+        limiter.delay(&other_messages, now),
+        Some(Duration::from_secs(5))
+    );
+    assert_eq!(limiter.delay(&messages, now), Some(Duration::from_secs(5)));
+    assert_eq!(limiter.delay(&messages, now + Duration::from_secs(5)), None);
+}
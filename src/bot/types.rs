@@ -1,6 +1,10 @@
 use crate::strings::StrCow;
 use chrono::{DateTime, Utc};
-use serde::{de::Error, Deserialize, Deserializer, Serialize};
+use serde::{
+    de::{Error, Unexpected, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::convert::TryFrom;
 use std::fmt::{Display, Formatter};
 use std::ops::Deref;
 use std::str::FromStr;
@@ -38,7 +42,7 @@ impl Display for Token {
     }
 }
 
-#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
 #[serde(into = "String")]
 pub struct Id(u64);
 
@@ -47,8 +51,47 @@ impl<'de> Deserialize<'de> for Id {
     where
         D: Deserializer<'de>,
     {
-        StrCow::deserialize(deserializer)
-            .and_then(|s| Ok(Id(s.as_str().parse().map_err(D::Error::custom)?)))
+        struct IdVisitor;
+
+        impl Visitor<'_> for IdVisitor {
+            type Value = Id;
+
+            fn expecting(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+                f.write_str("a snowflake, as a string or an integer")
+            }
+
+            fn visit_str<E: Error>(self, s: &str) -> Result<Id, E> {
+                s.parse().map(Id).map_err(E::custom)
+            }
+
+            // ETF sends snowflakes as integers
+            fn visit_u64<E: Error>(self, n: u64) -> Result<Id, E> {
+                Ok(Id(n))
+            }
+
+            // and those that fit in 32 bits as signed ones
+            fn visit_i64<E: Error>(self, n: i64) -> Result<Id, E> {
+                u64::try_from(n)
+                    .map(Id)
+                    .map_err(|_| E::invalid_value(Unexpected::Signed(n), &self))
+            }
+        }
+
+        deserializer.deserialize_any(IdVisitor)
+    }
+}
+
+/// Unix time in milliseconds of the first moment of 2015, which snowflakes count from.
+const DISCORD_EPOCH: i64 = 1_420_070_400_000;
+
+impl Id {
+    pub fn get(self) -> u64 {
+        self.0
+    }
+
+    /// Unix time in milliseconds at which the snowflake was made.
+    pub fn timestamp(self) -> i64 {
+        (self.0 >> 22) as i64 + DISCORD_EPOCH
     }
 }
 
@@ -75,7 +118,27 @@ impl Display for Id {
 #[derive(Serialize, Deserialize, Copy, Clone, Debug)]
 pub struct Sequence(pub usize);
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+/// One shard out of `count`, serialized as `[id, count]`.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(into = "[u64; 2]", from = "[u64; 2]")]
+pub struct ShardInfo {
+    pub id: u64,
+    pub count: u64,
+}
+
+impl From<[u64; 2]> for ShardInfo {
+    fn from([id, count]: [u64; 2]) -> Self {
+        ShardInfo { id, count }
+    }
+}
+
+impl From<ShardInfo> for [u64; 2] {
+    fn from(shard: ShardInfo) -> Self {
+        [shard.id, shard.count]
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum Status {
     Online,
@@ -85,13 +148,103 @@ pub enum Status {
     Offline,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// What the bot is shown to be doing, below its name.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Activity {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: ActivityType,
+    /// Text of a custom status.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+}
+
+impl Activity {
+    fn new(kind: ActivityType, name: impl Into<String>) -> Self {
+        Activity {
+            name: name.into(),
+            kind,
+            state: None,
+        }
+    }
+
+    /// "Playing `name`"
+    pub fn playing(name: impl Into<String>) -> Self {
+        Self::new(ActivityType::Playing, name)
+    }
+
+    /// "Listening to `name`"
+    pub fn listening(name: impl Into<String>) -> Self {
+        Self::new(ActivityType::Listening, name)
+    }
+
+    /// "Watching `name`"
+    pub fn watching(name: impl Into<String>) -> Self {
+        Self::new(ActivityType::Watching, name)
+    }
+
+    /// "Competing in `name`"
+    pub fn competing(name: impl Into<String>) -> Self {
+        Self::new(ActivityType::Competing, name)
+    }
+
+    /// Just `text`, without a verb in front.
+    pub fn custom(text: impl Into<String>) -> Self {
+        Activity {
+            state: Some(text.into()),
+            ..Self::new(ActivityType::Custom, "Custom Status")
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ActivityType {
+    Playing = 0,
+    Listening = 2,
+    Watching = 3,
+    Custom = 4,
+    Competing = 5,
+}
+
+impl Serialize for ActivityType {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_u8(*self as u8)
+    }
+}
+
+impl<'de> Deserialize<'de> for ActivityType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match u8::deserialize(deserializer)? {
+            0 => Ok(ActivityType::Playing),
+            2 => Ok(ActivityType::Listening),
+            3 => Ok(ActivityType::Watching),
+            4 => Ok(ActivityType::Custom),
+            5 => Ok(ActivityType::Competing),
+            n => Err(D::Error::custom(format_args!(
+                "activity type {} cannot be set by bots",
+                n
+            ))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Message<'a> {
     #[serde(borrow)]
     pub content: StrCow<'a>,
 
     pub id: Id,
     pub channel_id: Id,
+    /// Unset for direct messages.
+    #[serde(default)]
+    pub guild_id: Option<Id>,
 
     #[serde(deserialize_with = "deserialize_datetime_into_millis")]
     pub timestamp: i64,
@@ -152,7 +305,7 @@ impl From<Intent> for Intents {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Member<'a> {
     #[serde(borrow)]
     pub user: Option<User<'a>>,
@@ -160,7 +313,35 @@ pub struct Member<'a> {
     pub nick: Option<StrCow<'a>>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+/// A guild member that does not borrow from the event it arrived in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GuildMember {
+    pub id: Id,
+    pub username: String,
+    pub discriminator: String,
+    pub nick: Option<String>,
+}
+
+impl GuildMember {
+    /// The member's `username#discriminator`.
+    pub fn tag(&self) -> String {
+        format!("{}#{}", self.username, self.discriminator)
+    }
+}
+
+impl Member<'_> {
+    /// An owned copy of the member, if it came with its user.
+    pub fn to_guild_member(&self) -> Option<GuildMember> {
+        self.user.as_ref().map(|user| GuildMember {
+            id: user.id,
+            username: user.username.to_string(),
+            discriminator: user.discriminator.to_string(),
+            nick: self.nick.as_ref().map(|nick| nick.as_str().to_string()),
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct User<'a> {
     pub id: Id,
     pub username: &'a str, // might need Cow
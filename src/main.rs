@@ -1,31 +1,76 @@
 #![recursion_limit = "256"]
 #![deny(warnings)]
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 
 use crate::bot::client::Client;
-use crate::bot::message::event::DispatchPayload;
+use crate::bot::message::event::{DispatchPayload, GuildMembersChunk};
 use crate::markov::Markov;
+use bot::api_error::{ApiErrorCode, DiscordApiError};
+use bot::bus::{EventBus, IgnoreChannels, IgnoreOwnMessages};
+use bot::client::{
+    can_bulk_delete, AllowedMentions, CreateMessage, EditMessage, BULK_DELETE_RANGE,
+};
+use bot::embed::Embed;
+use bot::handle::GatewayHandle;
+use bot::message::command::RequestGuildMembers;
+use bot::multipart::Attachment;
 use bot::types::*;
-use bot::Bot;
+use bot::{Bot, ConnectionConfig, GatewayOptions};
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use futures::StreamExt;
 use rand::Rng;
 use serde::Deserialize;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufReader, Write};
+use std::time::{Duration, Instant};
 
 pub mod bot;
 pub mod markov;
 pub mod strings;
 
+/// How long to wait for the gateway to answer a member request.
+const MEMBER_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long admins have to confirm commands that cannot be undone.
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(30);
+/// Color of the embeds the bot replies with.
+const EMBED_COLOR: u32 = 0xcf6a32;
+/// How often the progress of learning a channel is shown.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Handles dispatches, possibly several at once, so its state is only borrowed between awaits.
 struct Handler<'a> {
-    markov: &'a mut Markov,
-    rng: rand::rngs::ThreadRng,
-    id: Option<Id>,
+    markov: RefCell<&'a mut Markov>,
+    rng: RefCell<rand::rngs::ThreadRng>,
     cfg: BotConfig,
+    gateway: GatewayHandle,
+    /// `username#discriminator` of members looked up so far.
+    member_tags: RefCell<HashMap<Id, String>>,
+    /// Whether the bot has announced it is up since the process started.
+    announced: Cell<bool>,
 }
 
-impl Handler<'_> {
-    async fn handle_message(&mut self, client: &Client, message: &Message<'_>) -> Result<()> {
+impl<'m> Handler<'m> {
+    fn new(markov: &'m mut Markov, cfg: BotConfig, gateway: GatewayHandle) -> Self {
+        Handler {
+            markov: RefCell::new(markov),
+            rng: RefCell::new(rand::thread_rng()),
+            cfg,
+            gateway,
+            member_tags: RefCell::new(HashMap::new()),
+            announced: Cell::new(false),
+        }
+    }
+
+    async fn handle_message(
+        &self,
+        client: &Client,
+        message: &Message<'_>,
+        shard: ShardInfo,
+    ) -> Result<()> {
         let (cmd, args) = match message.content.as_str().strip_prefix("eg!").and_then(|s| {
             let mut args = s.split_whitespace().filter(|a| !a.is_empty());
             args.next().map(|cmd| (cmd, args))
@@ -60,23 +105,29 @@ impl Handler<'_> {
 
         match_command! {
             (cmd, args) {
-                "mimic"() => self.mimic(client, message.channel_id).await?
+                "mimic"() => self.mimic(client, message).await?
                 "follows"(word) => {
                     println!("{}", word);
-                    self.create_list_message(client, message.channel_id, self.markov.what_follows(word)).await?;
+                    let follows = self.markov.borrow().what_follows(word);
+                    self.create_list_message(client, message, format!("What follows `{}`", word), follows).await?;
                 }
                 "starts"() => {
-                    self.create_list_message(client, message.channel_id, self.markov.what_starts()).await?;
+                    let starts = self.markov.borrow().what_starts();
+                    self.create_list_message(client, message, "How sentences start", starts).await?;
                 }
                 "save"() => self.save(client, message.channel_id).await?
+                "ping"() => self.ping(client, message.channel_id, shard).await?
                 "clean"() => self.clean(client, message).await?
+                "backup"() => self.backup(client, message).await?
+                "purge"(count) => self.purge(client, message, count).await?
+                "whois"(user) => self.whois(client, message, user.trim_start_matches("<@!").trim_start_matches("<@").trim_end_matches('>').parse()?).await?
                 "learn"(channel, max) => {
                     let max = match max.to_lowercase().as_str() {
                         "full" => None,
                         s => Some(s.parse()?)
                     };
                     let learn_channel_id = channel.trim_start_matches("<#").trim_end_matches(">").parse()?;
-                    self.learn_channel(client, message.channel_id, learn_channel_id, max).await?;
+                    self.learn_channel(client, message, learn_channel_id, max).await?;
                 }
             }
         }
@@ -84,13 +135,17 @@ impl Handler<'_> {
         Ok(())
     }
 
-    async fn add_emojis(&mut self, client: &Client, message: &Message<'_>) -> Result<()> {
-        let mut emoji = None;
-        if self.rng.gen_ratio(1, 50) {
-            emoji = Some("bonk:756521659938111602");
-        } else if self.rng.gen_ratio(1, 200) {
-            emoji = Some("💦");
-        }
+    async fn add_emojis(&self, client: &Client, message: &Message<'_>) -> Result<()> {
+        let emoji = {
+            let mut rng = self.rng.borrow_mut();
+            if rng.gen_ratio(1, 50) {
+                Some("bonk:756521659938111602")
+            } else if rng.gen_ratio(1, 200) {
+                Some("💦")
+            } else {
+                None
+            }
+        };
         if let Some(emoji) = emoji {
             client
                 .create_reaction(message.channel_id, message.id, emoji)
@@ -100,7 +155,7 @@ impl Handler<'_> {
     }
 
     async fn save(&self, client: &Client, channel: Id) -> Result<()> {
-        let result = save_markov(self.markov);
+        let result = save_markov(&self.markov.borrow());
         let msg = match &result {
             Ok(s) => format!("Successfully saved ({})", file_size_to_string(*s)),
             Err(_) => String::from("Error saving :("),
@@ -109,7 +164,27 @@ impl Handler<'_> {
         result.and(Ok(()))
     }
 
-    async fn handle_wot(&mut self, client: &Client, message: &Message<'_>) -> Result<()> {
+    async fn ping(&self, client: &Client, channel: Id, shard: ShardInfo) -> Result<()> {
+        let msg = match (
+            self.gateway.latency(shard.id),
+            self.gateway.average_latency(shard.id),
+        ) {
+            (Some(latency), Some(average)) => format!(
+                "Pong! Gateway latency is {}ms ({}ms on average)",
+                latency.as_millis(),
+                average.as_millis()
+            ),
+            _ => String::from("Pong! No heartbeat has been acknowledged yet"),
+        };
+        client.create_message(channel, &msg).await
+    }
+
+    async fn reply(&self, client: &Client, message: &Message<'_>) -> Result<()> {
+        self.handle_wot(client, message).await?;
+        self.engineer_gaming(client, message).await
+    }
+
+    async fn handle_wot(&self, client: &Client, message: &Message<'_>) -> Result<()> {
         if message
             .content
             .as_str()
@@ -123,7 +198,7 @@ impl Handler<'_> {
         Ok(())
     }
 
-    async fn engineer_gaming(&mut self, client: &Client, message: &Message<'_>) -> Result<()> {
+    async fn engineer_gaming(&self, client: &Client, message: &Message<'_>) -> Result<()> {
         if message.content.as_str().trim().to_ascii_lowercase() == "engineer gaming" {
             client
                 .create_message(message.channel_id, "https://youtu.be/DGdfzM780KY")
@@ -132,21 +207,34 @@ impl Handler<'_> {
         Ok(())
     }
 
-    async fn mimic(&mut self, client: &Client, channel: Id) -> Result<()> {
-        client
-            .create_message(
-                channel,
-                self.markov
-                    .generate_sequence(&mut self.rng)
-                    .fold(String::new(), |p, c| p + &c + " ")
-                    .as_str(),
-            )
+    async fn mimic(&self, client: &Client, message: &Message<'_>) -> Result<()> {
+        let sequence = self
+            .markov
+            .borrow()
+            .generate_sequence(&mut *self.rng.borrow_mut())
+            .fold(String::new(), |p, c| p + &c + " ");
+        self.reply_with_embed(client, message, Embed::new().with_description(sequence))
             .await
     }
 
-    async fn clean(&mut self, client: &Client, message: &Message<'_>) -> Result<()> {
+    /// Replies to `message` with `embed`, without pinging anyone.
+    async fn reply_with_embed(
+        &self,
+        client: &Client,
+        message: &Message<'_>,
+        embed: Embed,
+    ) -> Result<()> {
+        let reply = CreateMessage::new()
+            .with_embed(embed.with_color(EMBED_COLOR))
+            .reply_to(message)
+            .with_allowed_mentions(AllowedMentions::none());
+        client.send_message(message.channel_id, &reply).await?;
+        Ok(())
+    }
+
+    async fn clean(&self, client: &Client, message: &Message<'_>) -> Result<()> {
         if self.is_admin_message(message) {
-            let removed = self.markov.clean();
+            let removed = self.markov.borrow_mut().clean();
             client
                 .create_message(message.channel_id, &format!("Removed {} entries", removed))
                 .await
@@ -160,10 +248,73 @@ impl Handler<'_> {
         }
     }
 
+    /// Uploads everything learned so far, in the format of `markov.dat`.
+    async fn backup(&self, client: &Client, message: &Message<'_>) -> Result<()> {
+        if !self.is_admin_message(message) {
+            return client
+                .create_message(
+                    message.channel_id,
+                    "Watch it, string bean. You aren't an admin",
+                )
+                .await;
+        }
+        let data = bincode::serialize(&**self.markov.borrow())?;
+        let backup = CreateMessage::new()
+            .with_content(format!(
+                "Here's everything I know ({})",
+                file_size_to_string(data.len() as u64)
+            ))
+            .with_attachment(Attachment::from_bytes("markov.dat", data))
+            .reply_to(message);
+        match client.send_message(message.channel_id, &backup).await {
+            Err(e) if is_too_large(&e) => {
+                client
+                    .create_message(message.channel_id, "I know too much to upload it all")
+                    .await
+            }
+            result => result.map(drop),
+        }
+    }
+
+    /// Asks the author of `message` to confirm, and waits for them to answer `yes`.
+    async fn confirm(&self, client: &Client, message: &Message<'_>, warning: &str) -> Result<bool> {
+        let (channel, author) = (message.channel_id, message.author.id);
+        // collecting starts before asking, so that a quick answer is not missed
+        let mut answers = self.gateway.collect(
+            move |payload| match payload {
+                DispatchPayload::MessageCreate(m) => {
+                    m.channel_id == channel && m.author.id == author
+                }
+                _ => false,
+            },
+            CONFIRM_TIMEOUT,
+        );
+        client
+            .create_message(
+                channel,
+                &format!(
+                    "{} Say `yes` within {} seconds to go ahead",
+                    warning,
+                    CONFIRM_TIMEOUT.as_secs()
+                ),
+            )
+            .await?;
+        Ok(answers
+            .next()
+            .await
+            .map_or(false, |answer| match answer.payload() {
+                DispatchPayload::MessageCreate(m) => {
+                    m.content.as_str().trim().eq_ignore_ascii_case("yes")
+                }
+                _ => false,
+            }))
+    }
+
     async fn create_list_message(
-        &mut self,
+        &self,
         client: &Client,
-        channel: Id,
+        message: &Message<'_>,
+        title: impl Into<String>,
         iter: impl IntoIterator<Item = impl ToString>,
     ) -> Result<()> {
         let mut iter = iter.into_iter().peekable();
@@ -173,24 +324,82 @@ impl Handler<'_> {
             iter.fold(String::new(), |p, c| p + &c.to_string() + " ")
         };
 
-        client.create_message(channel, &string).await
+        let embed = Embed::new().with_title(title).with_description(string);
+        self.reply_with_embed(client, message, embed).await
     }
 
     async fn learn_channel(
-        &mut self,
+        &self,
         client: &Client,
-        return_channel: Id,
+        message: &Message<'_>,
         channel: Id,
         max: Option<usize>,
     ) -> Result<()> {
+        let report = CreateMessage::new()
+            .with_embed(learn_report("Learning\u{2026}", channel, 0))
+            .reply_to(message)
+            .with_allowed_mentions(AllowedMentions::none());
+        let mut report = client.send_message(message.channel_id, &report).await?;
+        let report = report.get_response().await?;
+        let report = (report.channel_id, report.id);
+
+        let previous = self.gateway.presence();
+        self.gateway
+            .set_activity(Some(Activity::custom("learning\u{2026}")));
+        let (embed, result) = match self.learn_messages(client, channel, max, report).await {
+            Ok(sum) => {
+                self.gateway.set_activity(Some(Activity::playing(format!(
+                    "learned from {} messages",
+                    sum
+                ))));
+                (learn_report("Done learning", channel, sum), Ok(()))
+            }
+            Err(e) => {
+                if let Some(previous) = previous {
+                    self.gateway.update_presence(previous);
+                } else {
+                    self.gateway.set_activity(None);
+                }
+                let (reason, result) = match e.downcast_ref::<DiscordApiError>().map(|e| e.code) {
+                    Some(ApiErrorCode::MissingAccess) | Some(ApiErrorCode::MissingPermissions) => {
+                        (format!("I'm not allowed to read <#{}>", channel), Ok(()))
+                    }
+                    Some(ApiErrorCode::UnknownChannel) => {
+                        (format!("There's no channel {}", channel), Ok(()))
+                    }
+                    _ => (String::from("Something went wrong"), Err(e)),
+                };
+                let embed = Embed::new()
+                    .with_title("Couldn't learn")
+                    .with_description(reason)
+                    .with_color(EMBED_COLOR);
+                (embed, result)
+            }
+        };
+        client
+            .edit_message(report.0, report.1, &EditMessage::new().with_embed(embed))
+            .await?;
+        result
+    }
+
+    /// Remembers up to `max` messages of `channel`, newest first. Returns how many were read.
+    ///
+    /// The number read so far is shown in `report`, a message given by its channel and ID.
+    async fn learn_messages(
+        &self,
+        client: &Client,
+        channel: Id,
+        max: Option<usize>,
+        report: (Id, Id),
+    ) -> Result<usize> {
         let mut oldest_id = None;
         let mut oldest_ts = None;
         let mut sum = 0;
+        let mut reported = Instant::now();
         loop {
             let mut response = client
                 .get_channel_messages(channel, oldest_id.take())
                 .await?;
-            let rate_limit_end = response.rate_limit_end();
             let messages = response.get_response().await?;
 
             sum += messages.len();
@@ -203,19 +412,159 @@ impl Handler<'_> {
             }
 
             if oldest_id.is_none() || max.map(|m| sum >= m).unwrap_or(false) {
+                break Ok(sum);
+            }
+
+            if reported.elapsed() >= PROGRESS_INTERVAL {
+                let progress = learn_report("Learning\u{2026}", channel, sum);
                 client
-                    .create_message(return_channel, &format!("learned from {} messages", sum))
+                    .edit_message(report.0, report.1, &EditMessage::new().with_embed(progress))
                     .await?;
-                break Ok(());
+                reported = Instant::now();
             }
+        }
+    }
 
-            if let Some(time) = rate_limit_end {
-                async_io::Timer::at(time).await;
+    /// Deletes the `count` messages before `message` in its channel, as far as they are recent
+    /// enough to be bulk deleted.
+    async fn purge(&self, client: &Client, message: &Message<'_>, count: &str) -> Result<()> {
+        let channel = message.channel_id;
+        if !self.is_admin_message(message) {
+            return client
+                .create_message(channel, "Watch it, string bean. You aren't an admin")
+                .await;
+        }
+        let count = match count.parse() {
+            Ok(count) if count > 0 && count <= *BULK_DELETE_RANGE.end() => count,
+            _ => {
+                let reply = format!(
+                    "I can purge 1 to {} messages at a time",
+                    BULK_DELETE_RANGE.end()
+                );
+                return client.create_message(channel, &reply).await;
+            }
+        };
+        let warning = format!("This deletes the last {} messages here.", count);
+        if !self.confirm(client, message, &warning).await? {
+            return client.create_message(channel, "Not purging then").await;
+        }
+
+        let mut doomed = Vec::with_capacity(count);
+        let mut before = Some(message.id);
+        let mut too_old = false;
+        while doomed.len() < count && !too_old {
+            let mut response = client.get_channel_messages(channel, before.take()).await?;
+            let mut page: Vec<Id> = response
+                .get_response()
+                .await?
+                .iter()
+                .map(|m| m.id)
+                .collect();
+            page.sort_by_key(|id| std::cmp::Reverse(id.get()));
+            for &id in page.iter().take(count - doomed.len()) {
+                if !can_bulk_delete(id) {
+                    too_old = true;
+                    break;
+                }
+                doomed.push(id);
+            }
+            before = match page.last() {
+                Some(&oldest) => Some(oldest),
+                None => break,
+            };
+        }
+
+        match doomed.len() {
+            0 => (),
+            1 => client.delete_message(channel, doomed[0]).await?,
+            _ => client.bulk_delete_messages(channel, &doomed).await?,
+        }
+        let mut reply = format!("Deleted {} messages", doomed.len());
+        if too_old {
+            reply += ". Older ones are too old to purge";
+        }
+        client.create_message(channel, &reply).await
+    }
+
+    async fn whois(&self, client: &Client, message: &Message<'_>, user: Id) -> Result<()> {
+        if !self.is_admin_message(message) {
+            return client
+                .create_message(
+                    message.channel_id,
+                    "Watch it, string bean. You aren't an admin",
+                )
+                .await;
+        }
+        let guild = match message.guild_id {
+            Some(guild) => guild,
+            None => {
+                return client
+                    .create_message(message.channel_id, "I can only look up guild members")
+                    .await
             }
+        };
+        self.look_up_members(guild, vec![user]).await?;
+        let reply = match self.member_tags.borrow().get(&user) {
+            Some(tag) => format!("{} is `{}`", user, tag),
+            None => format!("{} is not a member here", user),
+        };
+        client.create_message(message.channel_id, &reply).await
+    }
+
+    /// Asks for the mentioned users of a guild message that the message itself did not come
+    /// with. The answer is not waited for, so that learning never holds up the channel, and
+    /// `remember` puts their names in once it has arrived.
+    fn look_up_mentions(&self, message: &Message<'_>) -> Result<()> {
+        let guild = match message.guild_id {
+            Some(guild) => guild,
+            None => return Ok(()),
+        };
+        let unknown: Vec<Id> = mentioned_ids(message.content.as_str())
+            .filter(|id| {
+                !self.member_tags.borrow().contains_key(id)
+                    && !message.mentions.iter().any(|user| user.id == *id)
+            })
+            .collect();
+        if unknown.is_empty() {
+            return Ok(());
+        }
+        self.gateway
+            .send_guild_members_request(RequestGuildMembers::user_ids(guild, unknown))
+    }
+
+    /// Keeps the names of members the gateway sent, whoever asked for them.
+    fn receive_members(&self, chunk: &GuildMembersChunk<'_>) {
+        let mut member_tags = self.member_tags.borrow_mut();
+        for member in chunk.members.iter().filter_map(|m| m.to_guild_member()) {
+            member_tags.insert(member.id, member.tag());
         }
     }
 
-    fn remember(&mut self, message: &Message<'_>) {
+    async fn look_up_members(&self, guild: Id, users: Vec<Id>) -> Result<()> {
+        let request = self
+            .gateway
+            .request_guild_members(RequestGuildMembers::user_ids(guild, users));
+        let timeout = async_io::Timer::after(MEMBER_REQUEST_TIMEOUT);
+        let members = match future::select(Box::pin(request), timeout).await {
+            Either::Left((members, _)) => members?,
+            Either::Right(_) => bail!("timed out looking up guild members"),
+        };
+        let mut member_tags = self.member_tags.borrow_mut();
+        for member in members.members {
+            member_tags.insert(member.id, member.tag());
+        }
+        Ok(())
+    }
+
+    async fn learn(&self, _client: &Client, message: &Message<'_>) -> Result<()> {
+        if let Err(e) = self.look_up_mentions(message) {
+            eprintln!("{}", e);
+        }
+        self.remember(message);
+        Ok(())
+    }
+
+    fn remember(&self, message: &Message<'_>) {
         let words: Vec<_> = message
             .content
             .as_str()
@@ -228,6 +577,13 @@ impl Handler<'_> {
                                 return Some(format!("`{}#{}`", user.username, user.discriminator));
                             }
                         }
+                        if let Some(tag) = id
+                            .parse()
+                            .ok()
+                            .and_then(|id| self.member_tags.borrow().get(&id).cloned())
+                        {
+                            return Some(format!("`{}`", tag));
+                        }
                         Some(format!("`<@!{}>`", id))
                     } else {
                         Some(String::from(s))
@@ -238,7 +594,7 @@ impl Handler<'_> {
             })
             .collect();
         if words.len() >= 3 {
-            self.markov.insert_sequence(words);
+            self.markov.borrow_mut().insert_sequence(words);
         }
     }
 
@@ -250,10 +606,40 @@ impl Handler<'_> {
     }
 }
 
+/// Whether Discord rejected a request for being too large.
+fn is_too_large(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<DiscordApiError>(),
+        Some(e) if e.status == 413 || e.code == ApiErrorCode::RequestTooLarge
+    )
+}
+
+/// IDs of the users mentioned in `content`.
+fn mentioned_ids(content: &str) -> impl Iterator<Item = Id> + '_ {
+    content
+        .split_whitespace()
+        .filter_map(|s| s.strip_prefix("<@!").and_then(|s| s.strip_suffix('>')))
+        .filter_map(|id| id.parse().ok())
+}
+
+/// Saves `markov` to `markov.dat`. It is written to a temporary file first, so that an
+/// interrupted save never leaves a broken model behind.
 fn save_markov(markov: &Markov) -> Result<u64> {
-    let mut file = File::create("markov.dat")?;
+    let mut file = File::create("markov.dat.tmp")?;
     file.write_all(&bincode::serialize(markov)?)?;
-    Ok(file.metadata()?.len())
+    file.sync_all()?;
+    let size = file.metadata()?.len();
+    std::fs::rename("markov.dat.tmp", "markov.dat")?;
+    Ok(size)
+}
+
+/// Shows how far learning `channel` has come.
+fn learn_report(title: &str, channel: Id, sum: usize) -> Embed {
+    Embed::new()
+        .with_title(title)
+        .with_field("Channel", format!("<#{}>", channel), true)
+        .with_field("Messages", sum.to_string(), true)
+        .with_color(EMBED_COLOR)
 }
 
 fn file_size_to_string(size: u64) -> String {
@@ -268,44 +654,111 @@ fn file_size_to_string(size: u64) -> String {
     String::from("way too fricken big file!")
 }
 
-impl bot::AsyncDispatchHandler for Handler<'_> {
+/// Declares dispatch handlers that pass every message on to `Handler`.
+macro_rules! message_handlers {
+    ($(
+        $(#[$attr:meta])*
+        $name:ident |$handler:ident, $client:ident, $message:ident, $shard:pat| $body:expr;
+    )*) => {$(
+        $(#[$attr])*
+        struct $name<'h, 'm>(&'h Handler<'m>);
+
+        impl bot::AsyncDispatchHandler for $name<'_, '_> {
+            fn handle_message<'a>(
+                &'a self,
+                payload: DispatchPayload<'a>,
+                shard: ShardInfo,
+                client: &'a Client,
+            ) -> bot::AsyncDispatchFuture<'a> {
+                Box::pin(async move {
+                    match payload {
+                        DispatchPayload::MessageCreate(message) => {
+                            let ($handler, $client, $message, $shard) =
+                                (self.0, client, &message, shard);
+                            $body.await
+                        }
+                        _ => Ok(()),
+                    }
+                })
+            }
+        }
+    )*};
+}
+
+message_handlers! {
+    /// Reacts to messages now and then, even the bot's own.
+    Reactions |handler, client, message, _| handler.add_emojis(client, message);
+    /// Answers messages that call for it.
+    Replies |handler, client, message, _| handler.reply(client, message);
+    /// Runs `eg!` commands.
+    Commands |handler, client, message, shard| handler.handle_message(client, message, shard);
+    /// Learns from messages.
+    Learner |handler, client, message, _| handler.learn(client, message);
+}
+
+/// Announces that the bot is up, once the first shard's first session starts.
+struct Announcer<'h, 'm>(&'h Handler<'m>);
+
+impl bot::AsyncDispatchHandler for Announcer<'_, '_> {
     fn handle_message<'a>(
-        &'a mut self,
+        &'a self,
         payload: DispatchPayload<'a>,
+        shard: ShardInfo,
         client: &'a Client,
     ) -> bot::AsyncDispatchFuture<'a> {
         Box::pin(async move {
-            match payload {
-                DispatchPayload::MessageCreate(message) => {
-                    self.add_emojis(client, &message).await?;
-                    if self.id != Some(message.author.id) {
-                        self.handle_wot(client, &message).await?;
-                        self.engineer_gaming(client, &message).await?;
-                        self.handle_message(client, &message).await?;
-                        if !self
-                            .cfg
-                            .channel_blacklist
-                            .iter()
-                            .any(|&bc| bc == message.channel_id)
-                        {
-                            self.remember(&message);
-                        }
-                    }
-                    Ok(())
+            // sessions started again after a reconnect, and the other shards, are not news
+            if let DispatchPayload::Ready(_) = payload {
+                if shard.id != 0 || self.0.announced.replace(true) {
+                    return Ok(());
                 }
-                DispatchPayload::Ready(ready) => {
-                    self.id = Some(ready.user.id);
-                    for &chan in &self.cfg.announcement_channels {
-                        client.create_message(chan, "Dispenser goin' up!").await?;
-                    }
-                    Ok(())
+                for &chan in &self.0.cfg.announcement_channels {
+                    client.create_message(chan, "Dispenser goin' up!").await?;
                 }
-                _ => Ok(()),
             }
+            Ok(())
         })
     }
 }
 
+/// Keeps the names of members as they arrive.
+struct MemberTags<'h, 'm>(&'h Handler<'m>);
+
+impl bot::AsyncDispatchHandler for MemberTags<'_, '_> {
+    fn handle_message<'a>(
+        &'a self,
+        payload: DispatchPayload<'a>,
+        _shard: ShardInfo,
+        _client: &'a Client,
+    ) -> bot::AsyncDispatchFuture<'a> {
+        Box::pin(async move {
+            if let DispatchPayload::GuildMembersChunk(chunk) = payload {
+                self.0.receive_members(&chunk);
+            }
+            Ok(())
+        })
+    }
+}
+
+impl<'m> Handler<'m> {
+    /// Everything the bot does, as handlers of one event bus.
+    fn event_bus(&self) -> EventBus<'_> {
+        let learning = EventBus::new()
+            .with_middleware(IgnoreChannels(self.cfg.channel_blacklist.clone()))
+            .with_handler(0, Learner(self));
+        let responding = EventBus::new()
+            .with_middleware(IgnoreOwnMessages::default())
+            .with_handler(2, Replies(self))
+            .with_handler(1, Commands(self))
+            .with_handler(0, learning);
+        EventBus::new()
+            .with_handler(2, Reactions(self))
+            .with_handler(1, responding)
+            .with_handler(0, Announcer(self))
+            .with_handler(0, MemberTags(self))
+    }
+}
+
 #[derive(Deserialize)]
 struct BotConfig {
     token: TokenBuf,
@@ -313,20 +766,61 @@ struct BotConfig {
     admins: Vec<Id>,
     channel_blacklist: Vec<Id>,
     announcement_channels: Vec<Id>,
+    /// Sent to the announcement channels when shutting down.
+    #[serde(default)]
+    shutdown_message: Option<String>,
+    #[serde(default)]
+    gateway: GatewayOptions,
+    #[serde(default)]
+    connection: ConnectionConfig,
+}
+
+fn read_config() -> Result<BotConfig> {
+    let cfg: BotConfig = serde_json::from_reader(BufReader::new(File::open("bot.json")?))?;
+    cfg.gateway.validate(&cfg.connection)?;
+    Ok(cfg)
 }
 
 fn run(markov: &mut Markov) -> Result<()> {
-    let bot_cfg: BotConfig = serde_json::from_reader(BufReader::new(File::open("bot.json")?))?;
-
-    let bot = Bot::new(bot_cfg.token.clone(), bot_cfg.intents);
-    bot.run(Handler {
-        markov,
-        rng: rand::thread_rng(),
-        id: None,
-        cfg: bot_cfg,
+    let bot_cfg = read_config()?;
+
+    let bot = Bot::new(
+        bot_cfg.token.clone(),
+        bot_cfg.intents,
+        bot_cfg.gateway.clone(),
+        bot_cfg.connection.clone(),
+    );
+    let (stop, mut stopped) = mpsc::unbounded();
+    ctrlc::set_handler(move || {
+        let _ = stop.unbounded_send(());
+    })?;
+    let client = bot.client();
+    let channels = bot_cfg.announcement_channels.clone();
+    let shutdown_message = bot_cfg.shutdown_message.clone();
+    let handler = Handler::new(markov, bot_cfg, bot.handle());
+    bot.run_until(handler.event_bus(), async move {
+        stopped.next().await;
+        println!("shutting down");
+        if let Some(message) = shutdown_message {
+            announce_shutdown(client, &channels, &message).await;
+        }
     })
 }
 
+async fn announce_shutdown(client: &Client, channels: &[Id], message: &str) {
+    for &chan in channels {
+        if let Err(e) = client.create_message(chan, message).await {
+            eprintln!("{}", e);
+        }
+    }
+}
+
+/// Feeds a recording of gateway traffic to the handler, without connecting to anything.
+fn replay(markov: &mut Markov, path: &str) -> Result<()> {
+    let handler = Handler::new(markov, read_config()?, GatewayHandle::default());
+    bot::record::replay(path, handler.event_bus())
+}
+
 fn main() {
     let mut markov = File::open("markov.dat")
         .map_err(bincode::Error::from)
@@ -337,12 +831,325 @@ fn main() {
         })
         .unwrap_or_else(|_| Markov::new());
 
-    while let Err(e) = run(&mut markov) {
-        save_markov(&markov).unwrap();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.as_slice() {
+        // replays are for reproducing bugs, so what is learned from them is not kept
+        [flag, path] if flag == "--replay" => replay(&mut markov, path),
+        _ => {
+            let result = run(&mut markov);
+            match save_markov(&markov).context("cannot save markov.dat") {
+                Ok(_) => result,
+                Err(e) if result.is_ok() => Err(e),
+                // the run's own error is what gets reported below
+                Err(e) => {
+                    eprintln!("{:#}", e);
+                    result
+                }
+            }
+        }
+    };
+    if let Err(e) = result {
         for cause in e.chain() {
             println!("{}", cause);
         }
+        std::process::exit(1);
     }
+}
 
-    save_markov(&markov).unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bot::mock::*;
+    use serde_json::json;
+
+    const BOT_ID: u64 = 1;
+    const ANNOUNCEMENTS: u64 = 5;
+    const CHANNEL: u64 = 20;
+    const USER: u64 = 30;
+
+    fn setup() -> Result<(Bot, BotConfig, MockGateway, MockRest)> {
+        setup_with(|_| Response::json(200, json!({})))
+    }
+
+    fn setup_with(
+        responder: impl Fn(&Request) -> Response + Send + Sync + 'static,
+    ) -> Result<(Bot, BotConfig, MockGateway, MockRest)> {
+        let gateway = MockGateway::bind()?;
+        let rest = MockRest::with_responder(&gateway.url, responder)?;
+        let cfg: BotConfig = serde_json::from_value(json!({
+            "token": "test_token",
+            "intents": ["guild_messages"],
+            "admins": [],
+            "channel_blacklist": [],
+            "announcement_channels": [ANNOUNCEMENTS.to_string()],
+        }))?;
+        let bot = Bot::new(
+            cfg.token.clone(),
+            cfg.intents,
+            cfg.gateway.clone(),
+            connection_config(&rest),
+        );
+        Ok((bot, cfg, gateway, rest))
+    }
+
+    #[test]
+    fn announces_and_answers_messages() -> Result<()> {
+        let (bot, cfg, gateway, mut rest) = setup()?;
+        let mut markov = Markov::new();
+        let handler = Handler::new(&mut markov, cfg, bot.handle());
+        run_scripted(&bot, handler.event_bus(), async {
+            let mut conn = gateway.accept().await?;
+            conn.handshake("session", BOT_ID).await?;
+            let request = rest.expect_request("POST").await?;
+            assert_eq!(
+                request.path,
+                format!("/channels/{}/messages", ANNOUNCEMENTS)
+            );
+            assert_eq!(request.json()["content"], "Dispenser goin' up!");
+            // new sessions of a running bot are not announced again
+            conn.ready("again", BOT_ID).await?;
+
+            // the bot's own messages are ignored
+            conn.dispatch("MESSAGE_CREATE", message(1, CHANNEL, BOT_ID, "wot"))
+                .await?;
+            conn.dispatch("MESSAGE_CREATE", message(2, CHANNEL, USER, "well wot"))
+                .await?;
+            let request = rest.expect_request("POST").await?;
+            assert_eq!(request.path, format!("/channels/{}/messages", CHANNEL));
+            assert_eq!(request.json()["content"], "u wot m8");
+
+            conn.dispatch(
+                "MESSAGE_CREATE",
+                message(3, CHANNEL, USER, "engineer gaming"),
+            )
+            .await?;
+            let request = rest.expect_request("POST").await?;
+            assert_eq!(request.json()["content"], "https://youtu.be/DGdfzM780KY");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn mimics_learned_messages() -> Result<()> {
+        let (bot, cfg, gateway, mut rest) = setup()?;
+        let mut markov = Markov::new();
+        let handler = Handler::new(&mut markov, cfg, bot.handle());
+        run_scripted(&bot, handler.event_bus(), async {
+            let mut conn = gateway.accept().await?;
+            conn.handshake("session", BOT_ID).await?;
+            rest.expect_request("POST").await?;
+
+            conn.dispatch("MESSAGE_CREATE", message(1, CHANNEL, USER, "one two three"))
+                .await?;
+            conn.dispatch("MESSAGE_CREATE", message(2, CHANNEL, USER, "eg!mimic"))
+                .await?;
+            let request = rest.expect_request("POST").await?;
+            let reply = request.json();
+            assert_eq!(reply["embed"]["description"], "one two three ");
+            assert_eq!(reply["message_reference"]["message_id"], "2");
+            assert_eq!(reply["allowed_mentions"]["parse"], json!([]));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn remembers_names_of_unmentioned_members() -> Result<()> {
+        let (bot, cfg, gateway, mut rest) = setup()?;
+        let mut markov = Markov::new();
+        let handler = Handler::new(&mut markov, cfg, bot.handle());
+        run_scripted(&bot, handler.event_bus(), async {
+            let mut conn = gateway.accept().await?;
+            conn.handshake("session", BOT_ID).await?;
+            rest.expect_request("POST").await?;
+
+            let mut learned = message(1, CHANNEL, USER, "<@!77> says hi");
+            learned["guild_id"] = json!("9");
+            conn.dispatch("MESSAGE_CREATE", learned).await?;
+            let lookup = conn.expect_command(8).await?;
+            assert_eq!(lookup["guild_id"], "9");
+            assert_eq!(lookup["user_ids"], json!(["77"]));
+
+            // the channel is not held up waiting for the answer
+            conn.dispatch(
+                "MESSAGE_CREATE",
+                message(2, CHANNEL, USER, "eg!follows says"),
+            )
+            .await?;
+            let request = rest.expect_request("POST").await?;
+            assert_eq!(request.json()["embed"]["description"], "hi ");
+
+            conn.dispatch(
+                "GUILD_MEMBERS_CHUNK",
+                json!({
+                    "guild_id": "9",
+                    "members": [{ "user": user(77, "friend"), "nick": null }],
+                    "chunk_index": 0,
+                    "chunk_count": 1,
+                    "nonce": lookup["nonce"],
+                }),
+            )
+            .await?;
+            let mut learned = message(3, CHANNEL, USER, "hi <@!77> again");
+            learned["guild_id"] = json!("9");
+            conn.dispatch("MESSAGE_CREATE", learned).await?;
+
+            conn.dispatch("MESSAGE_CREATE", message(4, CHANNEL, USER, "eg!follows hi"))
+                .await?;
+            let request = rest.expect_request("POST").await?;
+            assert_eq!(request.json()["embed"]["description"], "`friend#0001` ");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn explains_why_a_channel_cannot_be_learned() -> Result<()> {
+        let (bot, mut cfg, gateway, mut rest) = setup_with(|request| {
+            if request.method == "GET" {
+                let body = json!({ "code": 50001, "message": "Missing Access" });
+                Response::json(403, body)
+            } else {
+                Response::json(200, message(100, CHANNEL, BOT_ID, ""))
+            }
+        })?;
+        cfg.admins.push(USER.to_string().parse()?);
+        let mut markov = Markov::new();
+        let handler = Handler::new(&mut markov, cfg, bot.handle());
+        run_scripted(&bot, handler.event_bus(), async {
+            let mut conn = gateway.accept().await?;
+            conn.handshake("session", BOT_ID).await?;
+            rest.expect_request("POST").await?;
+
+            let command = "eg!learn <#40> full";
+            conn.dispatch("MESSAGE_CREATE", message(1, CHANNEL, USER, command))
+                .await?;
+            rest.expect_request("POST").await?;
+            assert_eq!(
+                rest.expect_request("GET").await?.path,
+                "/channels/40/messages"
+            );
+            let request = rest.expect_request("PATCH").await?;
+            assert_eq!(request.path, format!("/channels/{}/messages/100", CHANNEL));
+            assert_eq!(
+                request.json()["embed"]["description"],
+                "I'm not allowed to read <#40>"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn uploads_backups_for_admins() -> Result<()> {
+        let (bot, mut cfg, gateway, mut rest) = setup()?;
+        cfg.admins.push(USER.to_string().parse()?);
+        let mut markov = Markov::new();
+        let handler = Handler::new(&mut markov, cfg, bot.handle());
+        let mut upload = Vec::new();
+        run_scripted(&bot, handler.event_bus(), async {
+            let mut conn = gateway.accept().await?;
+            conn.handshake("session", BOT_ID).await?;
+            rest.expect_request("POST").await?;
+
+            conn.dispatch("MESSAGE_CREATE", message(1, CHANNEL, USER, "one two three"))
+                .await?;
+            conn.dispatch("MESSAGE_CREATE", message(2, CHANNEL, USER, "eg!backup"))
+                .await?;
+            let request = rest.expect_request("POST").await?;
+            assert!(request.headers["content-type"].starts_with("multipart/form-data"));
+            upload = request.body;
+            Ok(())
+        })?;
+
+        let text = String::from_utf8_lossy(&upload);
+        assert!(text.contains("name=\"file0\"; filename=\"markov.dat\""));
+        let saved = bincode::serialize(&markov)?;
+        assert!(upload.windows(saved.len()).any(|part| part == &saved[..]));
+        Ok(())
+    }
+
+    #[test]
+    fn reports_learning_in_one_message() -> Result<()> {
+        let (bot, mut cfg, gateway, mut rest) =
+            setup_with(|request| match request.method.as_str() {
+                "GET" if request.path.contains("before") => Response::json(200, json!([])),
+                "GET" => Response::json(
+                    200,
+                    json!([
+                        message(11, 40, USER, "one two"),
+                        message(10, 40, USER, "three")
+                    ]),
+                ),
+                _ => Response::json(200, message(100, CHANNEL, BOT_ID, "")),
+            })?;
+        cfg.admins.push(USER.to_string().parse()?);
+        let mut markov = Markov::new();
+        let handler = Handler::new(&mut markov, cfg, bot.handle());
+        run_scripted(&bot, handler.event_bus(), async {
+            let mut conn = gateway.accept().await?;
+            conn.handshake("session", BOT_ID).await?;
+            rest.expect_request("POST").await?;
+
+            let command = "eg!learn <#40> full";
+            conn.dispatch("MESSAGE_CREATE", message(1, CHANNEL, USER, command))
+                .await?;
+            let request = rest.next_request().await?;
+            assert_eq!(request.method, "POST");
+            assert_eq!(request.json()["embed"]["title"], "Learning\u{2026}");
+            assert_eq!(rest.next_request().await?.method, "GET");
+            assert!(rest
+                .next_request()
+                .await?
+                .path
+                .starts_with("/channels/40/messages?before="));
+            let request = rest.next_request().await?;
+            assert_eq!(request.method, "PATCH");
+            assert_eq!(request.path, format!("/channels/{}/messages/100", CHANNEL));
+            let report = request.json();
+            assert_eq!(report["embed"]["title"], "Done learning");
+            assert_eq!(report["embed"]["fields"][1]["value"], "2");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn purges_recent_messages() -> Result<()> {
+        // snowflakes of messages made just now
+        let now = ((chrono::Utc::now().timestamp_millis() - 1_420_070_400_000) as u64) << 22;
+        let (bot, mut cfg, gateway, mut rest) = setup_with(move |request| {
+            if request.method == "GET" {
+                let recent = |n| message(now + n, CHANNEL, USER, "spam");
+                Response::json(200, json!([recent(1), recent(3), recent(2)]))
+            } else {
+                Response::json(200, json!({}))
+            }
+        })?;
+        cfg.admins.push(USER.to_string().parse()?);
+        let mut markov = Markov::new();
+        let handler = Handler::new(&mut markov, cfg, bot.handle());
+        run_scripted(&bot, handler.event_bus(), async {
+            let mut conn = gateway.accept().await?;
+            conn.handshake("session", BOT_ID).await?;
+            rest.expect_request("POST").await?;
+
+            let command = message(now + 10, CHANNEL, USER, "eg!purge 2");
+            conn.dispatch("MESSAGE_CREATE", command).await?;
+            rest.expect_request("POST").await?;
+            conn.dispatch("MESSAGE_CREATE", message(now + 11, CHANNEL, USER, "yes"))
+                .await?;
+            let request = rest.expect_request("GET").await?;
+            assert_eq!(
+                request.path,
+                format!("/channels/{}/messages?before={}", CHANNEL, now + 10)
+            );
+            let request = rest.expect_request("POST").await?;
+            assert_eq!(
+                request.path,
+                format!("/channels/{}/messages/bulk-delete", CHANNEL)
+            );
+            let deleted = json!([(now + 3).to_string(), (now + 2).to_string()]);
+            assert_eq!(request.json(), json!({ "messages": deleted }));
+            let request = rest.expect_request("POST").await?;
+            assert_eq!(request.json()["content"], "Deleted 2 messages");
+            Ok(())
+        })
+    }
 }
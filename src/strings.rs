@@ -2,7 +2,7 @@ use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::fmt::{Debug, Formatter};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct StrCow<'a>(#[serde(borrow)] Cow<'a, str>);
 
 impl<'a> Debug for StrCow<'a> {